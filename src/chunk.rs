@@ -1,55 +1,205 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use crate::diagnostics::Span;
+use crate::object::Obj;
 use crate::value::Value;
 
-#[derive(Copy, Clone)]
-pub(crate) enum Opcode {
-    OpReturn,
-    OpConstant,
-    OpConstantLong,
-    OpNegate,
-    OpAdd,
-    OpSubtract,
-    OpMultiply,
-    OpDivide,
+/// The most constants a chunk can hold, bounded by the three byte index of
+/// the long instruction forms.
+pub(crate) const MAX_CONSTANTS: usize = 1 << 24;
+
+/// How the operand bytes following an opcode are laid out.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Operands {
+    None,
+    /// A stack slot, upvalue index or argument count
+    Byte,
+    /// A one byte constant index
+    Constant,
+    /// A three byte little-endian constant index
+    ConstantLong,
+    /// A 16-bit big-endian offset forwards
+    Jump,
+    /// A 16-bit big-endian offset backwards
+    Loop,
+    /// A function constant, then an `is_local` and index byte per upvalue
+    Closure,
+    /// `Closure` with a three byte constant index
+    ClosureLong,
+    /// A method name constant, then an argument count
+    Invoke,
+    /// `Invoke` with a three byte constant index
+    InvokeLong,
 }
 
-impl From<u8> for Opcode {
-    fn from(byte: u8) -> Self {
-        match byte {
-            0 => Opcode::OpReturn,
-            1 => Opcode::OpConstant,
-            2 => Opcode::OpConstantLong,
-            3 => Opcode::OpNegate,
-            4 => Opcode::OpAdd,
-            5 => Opcode::OpSubtract,
-            6 => Opcode::OpDivide,
-            _ => panic!(),
+/// Defines every opcode once, along with its byte, the name it's
+/// disassembled as, its operands and how many values it leaves on the stack
+/// compared to before it ran. The stack effect is `None` where it depends on
+/// an operand or on the call being returned from.
+macro_rules! opcodes {
+    ($($opcode:ident = $byte:literal, $name:literal, $operands:ident, $effect:expr;)*) => {
+        #[allow(clippy::enum_variant_names)]
+        #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+        pub(crate) enum Opcode {
+            $($opcode = $byte,)*
         }
-    }
+
+        impl Opcode {
+            #[cfg(test)]
+            pub(crate) const ALL: &'static [Opcode] = &[$(Opcode::$opcode,)*];
+
+            pub(crate) fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$opcode => $name,)*
+                }
+            }
+
+            pub(crate) fn operands(self) -> Operands {
+                match self {
+                    $(Opcode::$opcode => Operands::$operands,)*
+                }
+            }
+
+            pub(crate) fn stack_effect(self) -> Option<i8> {
+                match self {
+                    $(Opcode::$opcode => $effect,)*
+                }
+            }
+        }
+
+        /// Fails with the byte itself if it isn't an opcode.
+        impl TryFrom<u8> for Opcode {
+            type Error = u8;
+
+            fn try_from(byte: u8) -> Result<Self, Self::Error> {
+                match byte {
+                    $($byte => Ok(Opcode::$opcode),)*
+                    _ => Err(byte),
+                }
+            }
+        }
+
+        impl From<Opcode> for u8 {
+            fn from(opcode: Opcode) -> Self {
+                opcode as u8
+            }
+        }
+    };
+}
+
+opcodes! {
+    OpReturn = 0, "OP_RETURN", None, None;
+    OpConstant = 1, "OP_CONSTANT", Constant, Some(1);
+    OpConstantLong = 2, "OP_CONSTANT_LONG", ConstantLong, Some(1);
+    OpNegate = 3, "OP_NEGATE", None, Some(0);
+    OpAdd = 4, "OP_ADD", None, Some(-1);
+    OpSubtract = 5, "OP_SUBTRACT", None, Some(-1);
+    OpMultiply = 6, "OP_MULTIPLY", None, Some(-1);
+    OpDivide = 7, "OP_DIVIDE", None, Some(-1);
+    OpNil = 8, "OP_NIL", None, Some(1);
+    OpTrue = 9, "OP_TRUE", None, Some(1);
+    OpFalse = 10, "OP_FALSE", None, Some(1);
+    OpNot = 11, "OP_NOT", None, Some(0);
+    OpEqual = 12, "OP_EQUAL", None, Some(-1);
+    OpGreater = 13, "OP_GREATER", None, Some(-1);
+    OpLess = 14, "OP_LESS", None, Some(-1);
+    OpPrint = 15, "OP_PRINT", None, Some(-1);
+    OpPop = 16, "OP_POP", None, Some(-1);
+    OpDefineGlobal = 17, "OP_DEFINE_GLOBAL", Constant, Some(-1);
+    OpGetGlobal = 18, "OP_GET_GLOBAL", Constant, Some(1);
+    OpSetGlobal = 19, "OP_SET_GLOBAL", Constant, Some(0);
+    OpGetLocal = 20, "OP_GET_LOCAL", Byte, Some(1);
+    OpSetLocal = 21, "OP_SET_LOCAL", Byte, Some(0);
+    OpPopN = 22, "OP_POPN", Byte, None;
+    OpJump = 23, "OP_JUMP", Jump, Some(0);
+    OpJumpIfFalse = 24, "OP_JUMP_IF_FALSE", Jump, Some(0);
+    OpLoop = 25, "OP_LOOP", Loop, Some(0);
+    OpCall = 26, "OP_CALL", Byte, None;
+    OpClosure = 27, "OP_CLOSURE", Closure, Some(1);
+    OpGetUpvalue = 28, "OP_GET_UPVALUE", Byte, Some(1);
+    OpSetUpvalue = 29, "OP_SET_UPVALUE", Byte, Some(0);
+    OpCloseUpvalue = 30, "OP_CLOSE_UPVALUE", None, Some(-1);
+    OpClass = 31, "OP_CLASS", Constant, Some(1);
+    OpGetProperty = 32, "OP_GET_PROPERTY", Constant, Some(0);
+    OpSetProperty = 33, "OP_SET_PROPERTY", Constant, Some(-1);
+    OpMethod = 34, "OP_METHOD", Constant, Some(-1);
+    OpInvoke = 35, "OP_INVOKE", Invoke, None;
+    OpInherit = 36, "OP_INHERIT", None, Some(-1);
+    OpGetSuper = 37, "OP_GET_SUPER", Constant, Some(-1);
+    OpSuperInvoke = 38, "OP_SUPER_INVOKE", Invoke, None;
+    OpDefineGlobalLong = 39, "OP_DEFINE_GLOBAL_LONG", ConstantLong, Some(-1);
+    OpGetGlobalLong = 40, "OP_GET_GLOBAL_LONG", ConstantLong, Some(1);
+    OpSetGlobalLong = 41, "OP_SET_GLOBAL_LONG", ConstantLong, Some(0);
+    OpClosureLong = 42, "OP_CLOSURE_LONG", ClosureLong, Some(1);
+    OpClassLong = 43, "OP_CLASS_LONG", ConstantLong, Some(1);
+    OpGetPropertyLong = 44, "OP_GET_PROPERTY_LONG", ConstantLong, Some(0);
+    OpSetPropertyLong = 45, "OP_SET_PROPERTY_LONG", ConstantLong, Some(-1);
+    OpMethodLong = 46, "OP_METHOD_LONG", ConstantLong, Some(-1);
+    OpInvokeLong = 47, "OP_INVOKE_LONG", InvokeLong, None;
+    OpGetSuperLong = 48, "OP_GET_SUPER_LONG", ConstantLong, Some(-1);
+    OpSuperInvokeLong = 49, "OP_SUPER_INVOKE_LONG", InvokeLong, None;
 }
 
-impl From<Opcode> for u8 {
-    fn from(opcode: Opcode) -> Self {
-        match opcode {
-            Opcode::OpReturn => 0,
-            Opcode::OpConstant => 1,
-            Opcode::OpConstantLong => 2,
-            Opcode::OpNegate => 3,
-            Opcode::OpAdd => 4,
-            Opcode::OpSubtract => 5,
-            Opcode::OpDivide => 6,
-            _ => panic!(),
+impl Opcode {
+    /// The form of an instruction taking a constant index that takes a three
+    /// byte index instead of one, so it can reach the whole constant pool.
+    pub(crate) fn long_form(self) -> Option<Opcode> {
+        match self {
+            Opcode::OpConstant => Some(Opcode::OpConstantLong),
+            Opcode::OpDefineGlobal => Some(Opcode::OpDefineGlobalLong),
+            Opcode::OpGetGlobal => Some(Opcode::OpGetGlobalLong),
+            Opcode::OpSetGlobal => Some(Opcode::OpSetGlobalLong),
+            Opcode::OpClosure => Some(Opcode::OpClosureLong),
+            Opcode::OpClass => Some(Opcode::OpClassLong),
+            Opcode::OpGetProperty => Some(Opcode::OpGetPropertyLong),
+            Opcode::OpSetProperty => Some(Opcode::OpSetPropertyLong),
+            Opcode::OpMethod => Some(Opcode::OpMethodLong),
+            Opcode::OpInvoke => Some(Opcode::OpInvokeLong),
+            Opcode::OpGetSuper => Some(Opcode::OpGetSuperLong),
+            Opcode::OpSuperInvoke => Some(Opcode::OpSuperInvokeLong),
+            _ => None,
+        }
+    }
+
+    /// The size in bytes of the constant index an instruction starts its
+    /// operands with.
+    pub(crate) fn constant_width(self) -> usize {
+        match self.operands() {
+            Operands::ConstantLong | Operands::ClosureLong | Operands::InvokeLong => 3,
+            _ => 1,
         }
     }
 }
 
-pub(crate) struct LineEncoding {
-    pub(crate) count: u8,
-    pub(crate) line: i32,
+/// A run of consecutive bytes in `code` compiled from the same token.
+pub(crate) struct PositionRun {
+    /// Offset of the first byte of the run
+    pub(crate) start: usize,
+    pub(crate) span: Span,
 }
 
-impl LineEncoding {
-    pub(crate) fn new(line: i32) -> Self {
-        Self { count: 1, line }
+/// Identifies a constant for deduplication. Numbers compare by their bits so
+/// `-0.0` stays apart from `0.0` and `NaN` can be reused, objects by identity
+/// which for strings means by contents since they're interned.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    Obj(*mut Obj),
+}
+
+impl From<Value> for ConstantKey {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => ConstantKey::Nil,
+            Value::Bool(b) => ConstantKey::Bool(b),
+            Value::Number(n) => ConstantKey::Number(n.to_bits()),
+            Value::Obj(object) => ConstantKey::Obj(object),
+        }
     }
 }
 
@@ -57,7 +207,14 @@ impl LineEncoding {
 pub(crate) struct Chunk {
     pub(crate) code: Vec<u8>,
     pub(crate) constants: Vec<Value>,
-    pub(crate) lines: Vec<LineEncoding>,
+    pub(crate) positions: Vec<PositionRun>,
+    /// The source `positions` points into, kept for runtime errors since each
+    /// REPL line is compiled on its own
+    pub(crate) source: Rc<str>,
+    /// Only needed while the chunk is being compiled, see `finish`
+    constant_indices: HashMap<ConstantKey, usize>,
+    /// How many times `add_constant` reused an existing constant
+    pub(crate) constants_reused: usize,
 }
 
 impl Chunk {
@@ -65,50 +222,186 @@ impl Chunk {
         Default::default()
     }
 
-    pub(crate) fn write_chunk(chunk: &mut Chunk, byte: u8, line: i32) {
-        chunk.code.push(byte.into());
+    /// Appends `byte`, recording `span` as the token it was compiled from.
+    pub(crate) fn write_chunk(chunk: &mut Chunk, byte: u8, span: Span) {
+        let continues_run = chunk.positions.last().is_some_and(|run| run.span == span);
+
+        if !continues_run {
+            chunk.positions.push(PositionRun {
+                start: chunk.code.len(),
+                span,
+            });
+        }
 
-        if let Some(prev) = chunk.lines.last_mut() {
-            if prev.line == line {
-                prev.count += 1;
-            } else {
-                chunk.lines.push(LineEncoding::new(line));
+        chunk.code.push(byte);
+    }
+
+    /// Returns the index of `value` in the constant pool, only adding it if
+    /// an identical constant isn't already there.
+    pub(crate) fn add_constant(chunk: &mut Chunk, value: Value) -> usize {
+        match chunk.constant_indices.entry(value.into()) {
+            Entry::Occupied(entry) => {
+                chunk.constants_reused += 1;
+                *entry.get()
+            }
+            Entry::Vacant(entry) => {
+                chunk.constants.push(value);
+                *entry.insert(chunk.constants.len() - 1)
             }
+        }
+    }
+
+    /// Frees what's only needed while compiling, once `chunk` is complete.
+    /// Constants added afterwards are no longer deduplicated.
+    pub(crate) fn finish(chunk: &mut Chunk) {
+        mem::take(&mut chunk.constant_indices);
+    }
+
+    /// Writes `opcode` followed by its index operand, switching to the long
+    /// form of the instruction once the index doesn't fit a byte. Indexes
+    /// past a byte must be constants below `MAX_CONSTANTS`.
+    pub(crate) fn write_with_operand(
+        chunk: &mut Chunk,
+        opcode: Opcode,
+        operand: usize,
+        span: Span,
+    ) {
+        if let Ok(operand) = u8::try_from(operand) {
+            Chunk::write_chunk(chunk, opcode.into(), span);
+            Chunk::write_chunk(chunk, operand, span);
         } else {
-            chunk.lines.push(LineEncoding::new(line));
+            let long_form = opcode
+                .long_form()
+                .expect("Only constant indexes can be wider than a byte");
+            let [low, middle, high, _] = (operand as u32).to_le_bytes();
+
+            Chunk::write_chunk(chunk, long_form.into(), span);
+            Chunk::write_chunk(chunk, low, span);
+            Chunk::write_chunk(chunk, middle, span);
+            Chunk::write_chunk(chunk, high, span);
         }
     }
 
-    pub(crate) fn add_constant(chunk: &mut Chunk, value: Value) -> u8 {
-        chunk.constants.push(value);
-        (chunk.constants.len() - 1).try_into().unwrap()
+    /// The span of the token the byte at `index` was compiled from.
+    pub(crate) fn get_span(index: usize, positions: &[PositionRun]) -> Span {
+        // The run containing `index` is the last one starting at or before it
+        let run = positions.partition_point(|run| run.start <= index);
+        positions[run.checked_sub(1).expect("Every byte has a position")].span
     }
+}
 
-    pub(crate) fn write_constant(chunk: &mut Chunk, value: Value, line: i32) {
-        chunk.constants.push(value);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let opcode;
+    #[test]
+    fn every_opcode_round_trips_through_its_byte() {
+        for &opcode in Opcode::ALL {
+            assert_eq!(Opcode::try_from(u8::from(opcode)), Ok(opcode));
+        }
+    }
 
-        if chunk.constants.len() > 255 {
-            opcode = Opcode::OpConstantLong;
-        } else {
-            opcode = Opcode::OpConstant;
+    #[test]
+    fn only_opcode_bytes_decode() {
+        for byte in 0..=u8::MAX {
+            let is_opcode = Opcode::ALL.iter().any(|&opcode| u8::from(opcode) == byte);
+            assert_eq!(Opcode::try_from(byte).is_ok(), is_opcode, "byte {}", byte);
         }
+    }
+
+    #[test]
+    fn constants_are_deduplicated_by_bits() {
+        let mut chunk = Chunk::new();
 
-        Chunk::write_chunk(chunk, opcode.into(), line);
+        let zero = Chunk::add_constant(&mut chunk, Value::Number(0.0));
+        let negative_zero = Chunk::add_constant(&mut chunk, Value::Number(-0.0));
+        let nan = Chunk::add_constant(&mut chunk, Value::Number(f64::NAN));
+
+        assert_ne!(zero, negative_zero);
+        assert_eq!(Chunk::add_constant(&mut chunk, Value::Number(0.0)), zero);
+        assert_eq!(
+            Chunk::add_constant(&mut chunk, Value::Number(f64::NAN)),
+            nan
+        );
+        assert_eq!(chunk.constants.len(), 3);
+        assert_eq!(chunk.constants_reused, 2);
     }
 
-    pub(crate) fn get_line(index: usize, lines: &Vec<LineEncoding>) -> i32 {
-        let mut total = 0;
+    #[test]
+    fn finishing_frees_the_index_but_keeps_the_constants() {
+        let mut chunk = Chunk::new();
 
-        for line_encoding in lines.iter() {
-            total += line_encoding.count;
+        Chunk::add_constant(&mut chunk, Value::Number(1.0));
+        Chunk::add_constant(&mut chunk, Value::Number(1.0));
+        Chunk::finish(&mut chunk);
 
-            if index + 1 <= total.into() {
-                return line_encoding.line;
-            }
+        assert_eq!(chunk.constants.len(), 1);
+        assert_eq!(chunk.constants_reused, 1);
+        assert_eq!(chunk.constant_indices.capacity(), 0);
+    }
+
+    #[test]
+    fn spans_survive_long_runs() {
+        let mut chunk = Chunk::new();
+        let nil = Span::new(4, 3, 1, 5);
+        let semicolon = Span::new(8, 1, 1, 9);
+        let end = Span::new(10, 0, 2, 1);
+
+        for _ in 0..1000 {
+            Chunk::write_chunk(&mut chunk, Opcode::OpNil.into(), nil);
+        }
+        Chunk::write_chunk(&mut chunk, Opcode::OpPop.into(), semicolon);
+        Chunk::write_chunk(&mut chunk, Opcode::OpReturn.into(), end);
+
+        assert_eq!(chunk.positions.len(), 3);
+        assert_eq!(Chunk::get_span(0, &chunk.positions), nil);
+        assert_eq!(Chunk::get_span(999, &chunk.positions), nil);
+        assert_eq!(Chunk::get_span(1000, &chunk.positions), semicolon);
+        assert_eq!(Chunk::get_span(1001, &chunk.positions), end);
+    }
+
+    #[test]
+    fn long_forms_only_widen_the_constant_index() {
+        for &opcode in Opcode::ALL {
+            let Some(long_form) = opcode.long_form() else {
+                continue;
+            };
+
+            assert_eq!(opcode.constant_width(), 1, "{}", opcode.name());
+            assert_eq!(long_form.constant_width(), 3, "{}", long_form.name());
+            assert_eq!(long_form.stack_effect(), opcode.stack_effect());
+            assert_eq!(long_form.name(), format!("{}_LONG", opcode.name()));
         }
+    }
 
-        panic!()
+    #[test]
+    fn operands_past_a_byte_use_the_long_form() {
+        let mut chunk = Chunk::new();
+
+        let span = Span::new(0, 1, 1, 1);
+
+        Chunk::write_with_operand(&mut chunk, Opcode::OpGetGlobal, 255, span);
+        Chunk::write_with_operand(&mut chunk, Opcode::OpGetGlobal, 0x012345, span);
+
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::OpGetGlobal.into(),
+                255,
+                Opcode::OpGetGlobalLong.into(),
+                0x45,
+                0x23,
+                0x01
+            ]
+        );
+    }
+
+    #[test]
+    fn opcode_names_are_unique() {
+        for (i, a) in Opcode::ALL.iter().enumerate() {
+            for b in &Opcode::ALL[i + 1..] {
+                assert_ne!(a.name(), b.name());
+            }
+        }
     }
 }
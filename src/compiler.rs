@@ -1,27 +1,1346 @@
-use crate::scanner::{Scanner, ScanResult};
+use std::rc::Rc;
 
-pub(crate) fn compile(source: &str) {
-    let mut line = -1;
-    let mut scanner = Scanner::new(source);
+use crate::chunk::{Chunk, Opcode, MAX_CONSTANTS};
+use crate::debug::Disassembler;
+use crate::diagnostics::{Diagnostic, Location, Span};
+use crate::object::ObjFunction;
+use crate::scanner::{EOFToken, ScanResult, Scanner, Token, TokenType};
+use crate::value::Value;
+use crate::vm::VM;
 
-    loop {
-	let result = scanner.scan_token();
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
 
-	match result {
-	    ScanResult::Normal(mut token) => {
-		if token.line != line {
-		    print!("{:04} ", token.line);
-		    line = token.line;
-		} else {
-		    print!("    | ");
-		}
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+struct Local {
+    name: String,
+    /// `None` while the variable's initializer is still being compiled
+    depth: Option<usize>,
+    is_captured: bool,
+    /// Where the variable was declared, for notes in diagnostics
+    span: Span,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Upvalue {
+    index: u8,
+    /// Whether the upvalue captures a local of the immediately enclosing
+    /// function, rather than one of its upvalues
+    is_local: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Everything the compiler tracks about the function whose body it is
+/// currently in. Nested function declarations push a new state.
+struct FunctionState {
+    function: *mut ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+/// Tracks the class whose body is being compiled, innermost last, so `this`
+/// and `super` can be rejected where they have no meaning.
+struct ClassState {
+    has_superclass: bool,
+}
+
+struct Compiler<'a> {
+    scanner: Scanner<'a>,
+    /// Shared with every chunk compiled from it and every diagnostic
+    /// pointing into it
+    source: Rc<str>,
+    current: ScanResult<'a>,
+    previous: ScanResult<'a>,
+    vm: &'a mut VM,
+    /// Set once an error has been reported
+    had_error: bool,
+    diagnostics: Vec<Diagnostic>,
+    /// Set after an error until the parser resynchronizes, so the errors it
+    /// causes further along aren't reported too
+    panic_mode: bool,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+}
+
+/// Compiles `source` into the function for its top level, or returns every
+/// error found in it.
+pub(crate) fn compile(source: &str, vm: &mut VM) -> Result<*mut ObjFunction, Vec<Diagnostic>> {
+    let mut compiler = Compiler::new(source, vm);
+
+    compiler.program();
+    let (function, _) = compiler.end_compiler();
+
+    if compiler.had_error {
+        Err(compiler.diagnostics)
+    } else {
+        Ok(function)
+    }
+}
+
+impl<'a> Compiler<'a> {
+    fn new(source: &'a str, vm: &'a mut VM) -> Self {
+        let mut compiler = Self {
+            scanner: Scanner::new(source),
+            source: Rc::from(source),
+            current: ScanResult::EOF(EOFToken::new(0, 1, 1)),
+            previous: ScanResult::EOF(EOFToken::new(0, 1, 1)),
+            vm,
+            had_error: false,
+            diagnostics: Vec::new(),
+            panic_mode: false,
+            states: Vec::new(),
+            classes: Vec::new(),
+        };
+
+        compiler.push_state(FunctionType::Script);
+        compiler
+    }
+
+    fn push_state(&mut self, function_type: FunctionType) {
+        let function = self.vm.new_function();
+        self.vm.compiler_roots.push(function);
+        unsafe {
+            (*function).chunk.source = Rc::clone(&self.source);
+        }
+
+        if function_type != FunctionType::Script {
+            let name = self.previous_token().lexeme;
+            unsafe {
+                (*function).name = self.vm.copy_string(name);
+            }
+        }
+
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+
+        // The callee itself occupies stack slot zero, except in methods where
+        // the receiver does and is reachable as `this`
+        locals.push(Local {
+            name: match function_type {
+                FunctionType::Function | FunctionType::Script => String::new(),
+                FunctionType::Initializer | FunctionType::Method => "this".to_string(),
+            },
+            depth: Some(0),
+            is_captured: false,
+            span: self.previous.span(),
+        });
+
+        self.states.push(FunctionState {
+            function,
+            function_type,
+            locals,
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        });
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states
+            .last()
+            .expect("A function is always being compiled")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states
+            .last_mut()
+            .expect("A function is always being compiled")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        unsafe { &mut (*self.state().function).chunk }
+    }
+
+    /// Anything that can grow the chunk goes through here so the collector
+    /// counts it.
+    fn write_chunk<T>(&mut self, write: impl FnOnce(&mut Chunk) -> T) -> T {
+        let chunk: *mut Chunk = self.current_chunk();
+        self.vm.grow_chunk(chunk, write)
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.scanner.scan_token();
+        std::mem::swap(&mut self.previous, &mut self.current);
+
+        // Lexical errors are reported as they're found and skipped, so a
+        // single pass reports all of them, but the parse errors they cause
+        // are suppressed
+        while let ScanResult::Error(error) = &self.current {
+            let diagnostic = Diagnostic::new(error.message, self.location(self.current.span()));
+            self.diagnostics.push(diagnostic);
+            self.had_error = true;
+            self.panic_mode = true;
+            self.current = self.scanner.scan_token();
+        }
+    }
+
+    fn consume(&mut self, r#type: TokenType, message: &str) {
+        if self.check(r#type) {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    fn check_eof(&self) -> bool {
+        matches!(self.current, ScanResult::EOF(_))
+    }
+
+    fn matches(&mut self, r#type: TokenType) -> bool {
+        if !self.check(r#type) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
+    fn check(&self, r#type: TokenType) -> bool {
+        matches!(&self.current, ScanResult::Normal(token) if token.r#type == r#type)
+    }
+
+    fn previous_token(&self) -> &Token<'a> {
+        match &self.previous {
+            ScanResult::Normal(token) => token,
+            _ => unreachable!("Only called after a normal token has been matched"),
+        }
+    }
+
+    fn previous_type(&self) -> Option<TokenType> {
+        match &self.previous {
+            ScanResult::Normal(token) => Some(token.r#type),
+            _ => None,
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        self.emit_byte_at(byte, self.previous.span());
+    }
+
+    /// Emits `byte` as compiled from `span`, for instructions that are only
+    /// emitted after their operands but can fail at runtime because of the
+    /// token before them.
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        self.write_chunk(|chunk| Chunk::write_chunk(chunk, byte, span));
+    }
+
+    fn emit_bytes_at(&mut self, byte1: u8, byte2: u8, span: Span) {
+        self.emit_byte_at(byte1, span);
+        self.emit_byte_at(byte2, span);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    /// Emits `opcode` with an index operand, switching to its long form if
+    /// the index is a constant past the first 256.
+    fn emit_with_operand(&mut self, opcode: Opcode, operand: usize) {
+        self.emit_with_operand_at(opcode, operand, self.previous.span());
+    }
+
+    fn emit_with_operand_at(&mut self, opcode: Opcode, operand: usize, span: Span) {
+        self.write_chunk(|chunk| Chunk::write_with_operand(chunk, opcode, operand, span));
+    }
+
+    /// Emits `instruction` with a placeholder operand and returns the offset
+    /// of that operand so it can be patched once the target is known.
+    fn emit_jump(&mut self, instruction: Opcode) -> usize {
+        self.emit_byte(instruction.into());
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.current_chunk().code[offset] = high;
+        self.current_chunk().code[offset + 1] = low;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(Opcode::OpLoop.into());
+
+        // +2 to skip over the operand we're about to emit
+        let offset = self.current_chunk().code.len() - loop_start + 2;
 
-		println!("{:02}", token.get_lexeme());
-	    },
-	    ScanResult::EOF(_) => break,
-	    ScanResult::Error(e) => panic!("{}", e.message),
-	}
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
 
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_bytes(high, low);
     }
 
+    fn emit_return(&mut self) {
+        // An initializer implicitly returns the instance it initialized
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_bytes(Opcode::OpGetLocal.into(), 0);
+        } else {
+            self.emit_byte(Opcode::OpNil.into());
+        }
+
+        self.emit_byte(Opcode::OpReturn.into());
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        if self.current_chunk().constants.len() == MAX_CONSTANTS {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+
+        self.write_chunk(|chunk| Chunk::add_constant(chunk, value))
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_with_operand(Opcode::OpConstant, constant);
+    }
+
+    fn end_compiler(&mut self) -> (*mut ObjFunction, Vec<Upvalue>) {
+        self.emit_return();
+
+        let state = self
+            .states
+            .pop()
+            .expect("A function is always being compiled");
+        self.vm.compiler_roots.pop();
+        Chunk::finish(unsafe { &mut (*state.function).chunk });
+
+        if self.vm.debug_print_code && !self.had_error {
+            let function = unsafe { &*state.function };
+            let name = match state.function_type {
+                FunctionType::Script => String::from("<script>"),
+                _ => unsafe { (*function.name).chars.clone() },
+            };
+
+            Disassembler::new(&function.chunk, &name).disassemble_chunk();
+        }
+
+        (state.function, state.upvalues)
+    }
+
+    fn program(&mut self) {
+        self.advance();
+
+        while !self.check_eof() {
+            self.declaration();
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn declaration(&mut self) {
+        if self.matches(TokenType::Class) {
+            self.class_declaration()
+        } else if self.matches(TokenType::Fun) {
+            self.fun_declaration()
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    /// Skips tokens until what is likely the start of the next statement, so
+    /// parsing can carry on after an error.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while !self.check_eof() {
+            if self.previous_type() == Some(TokenType::Semicolon) {
+                return;
+            }
+
+            if let ScanResult::Normal(token) = &self.current {
+                match token.r#type {
+                    TokenType::Class
+                    | TokenType::Fun
+                    | TokenType::Var
+                    | TokenType::For
+                    | TokenType::If
+                    | TokenType::While
+                    | TokenType::Print
+                    | TokenType::Return => return,
+                    _ => (),
+                }
+            }
+
+            self.advance();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous_token().lexeme;
+        let class_span = self.previous.span();
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_with_operand(Opcode::OpClass, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if self.matches(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name == self.previous_token().lexeme {
+                self.report(
+                    Diagnostic::new(
+                        "A class can't inherit from itself.",
+                        self.location(self.previous.span()),
+                    )
+                    .with_note("class declared here", Some(self.location(class_span))),
+                );
+            }
+
+            // Methods capture the superclass through this local as `super`,
+            // in a scope of its own so sibling classes don't share it
+            self.begin_scope();
+            self.add_local("super".to_string(), self.previous.span());
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_byte(Opcode::OpInherit.into());
+
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // Load the class back onto the stack so methods can be bound to it
+        self.named_variable(class_name, false);
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check_eof() {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+
+        self.emit_byte(Opcode::OpPop.into());
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous_token().lexeme;
+        let constant = self.identifier_constant(name);
+
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+
+        self.function(function_type);
+        self.emit_with_operand(Opcode::OpMethod, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+
+        // A function may refer to itself, so it's usable before its body ends
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        self.push_state(function_type);
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+
+        if !self.check(TokenType::RightParen) {
+            loop {
+                let function = self.state().function;
+
+                let arity = unsafe {
+                    (*function).arity += 1;
+                    (*function).arity
+                };
+
+                if arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No end_scope, the whole frame is discarded when the function returns
+        let (function, upvalues) = self.end_compiler();
+        let constant = self.make_constant(function.into());
+        self.emit_with_operand(Opcode::OpClosure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local.into(), upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.matches(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(Opcode::OpNil.into());
+        }
+
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.matches(TokenType::Print) {
+            self.print_statement()
+        } else if self.matches(TokenType::Return) {
+            self.return_statement()
+        } else if self.matches(TokenType::For) {
+            self.for_statement()
+        } else if self.matches(TokenType::If) {
+            self.if_statement()
+        } else if self.matches(TokenType::While) {
+            self.while_statement()
+        } else if self.matches(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check_eof() {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        let mut popped = 0usize;
+
+        while let Some(local) = self.state().locals.last() {
+            if local
+                .depth
+                .is_some_and(|depth| depth <= self.state().scope_depth)
+            {
+                break;
+            }
+
+            // Captured locals have to be hoisted individually, so flush any
+            // run of plain pops before them
+            if local.is_captured {
+                self.emit_pops(popped);
+                popped = 0;
+                self.emit_byte(Opcode::OpCloseUpvalue.into());
+            } else {
+                popped += 1;
+            }
+
+            self.state_mut().locals.pop();
+        }
+
+        self.emit_pops(popped);
+    }
+
+    fn emit_pops(&mut self, mut popped: usize) {
+        while popped > 0 {
+            let count = popped.min(u8::MAX as usize);
+
+            if count == 1 {
+                self.emit_byte(Opcode::OpPop.into());
+            } else {
+                self.emit_bytes(Opcode::OpPopN.into(), count as u8);
+            }
+
+            popped -= count;
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(Opcode::OpJumpIfFalse);
+        self.emit_byte(Opcode::OpPop.into());
+        self.statement();
+
+        let else_jump = self.emit_jump(Opcode::OpJump);
+        self.patch_jump(then_jump);
+        self.emit_byte(Opcode::OpPop.into());
+
+        if self.matches(TokenType::Else) {
+            self.statement();
+        }
+
+        self.patch_jump(else_jump)
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(Opcode::OpJumpIfFalse);
+        self.emit_byte(Opcode::OpPop.into());
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(Opcode::OpPop.into());
+    }
+
+    /// A `for` loop is desugared into the equivalent `while` loop wrapped in
+    /// its own scope, with the increment clause jumped over on the first pass.
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+
+        if self.matches(TokenType::Semicolon) {
+            // No initializer
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+
+        if !self.matches(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(Opcode::OpJumpIfFalse));
+            self.emit_byte(Opcode::OpPop.into());
+        }
+
+        if !self.matches(TokenType::RightParen) {
+            let body_jump = self.emit_jump(Opcode::OpJump);
+            let increment_start = self.current_chunk().code.len();
+
+            self.expression();
+            self.emit_byte(Opcode::OpPop.into());
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            // Condition
+            self.emit_byte(Opcode::OpPop.into());
+        }
+
+        self.end_scope();
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.matches(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(Opcode::OpReturn.into());
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(Opcode::OpPrint.into());
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(Opcode::OpPop.into());
+    }
+
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.previous_token().lexeme)
+    }
+
+    fn declare_variable(&mut self) {
+        let scope_depth = self.state().scope_depth;
+
+        if scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous_token().lexeme;
+
+        for local in self.state().locals.iter().rev() {
+            if local.depth.is_some_and(|depth| depth < scope_depth) {
+                break;
+            }
+
+            if local.name == name {
+                let declared = local.span;
+                self.report(
+                    Diagnostic::new(
+                        "Already a variable with this name in this scope.",
+                        self.location(self.previous.span()),
+                    )
+                    .with_note("variable declared here", Some(self.location(declared))),
+                );
+                break;
+            }
+        }
+
+        self.add_local(name.to_string(), self.previous.span())
+    }
+
+    fn add_local(&mut self, name: String, span: Span) {
+        if self.state().locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+            span,
+        });
+    }
+
+    fn resolve_local(&mut self, state: usize, name: &str) -> Option<u8> {
+        match self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+        {
+            Some((
+                slot,
+                Local {
+                    depth: None, span, ..
+                },
+            )) => {
+                let declared = *span;
+                self.report(
+                    Diagnostic::new(
+                        "Can't read local variable in its own initializer.",
+                        self.location(self.previous.span()),
+                    )
+                    .with_note("variable declared here", Some(self.location(declared))),
+                );
+                Some(slot as u8)
+            }
+            Some((slot, _)) => Some(slot as u8),
+            None => None,
+        }
+    }
+
+    /// Looks `name` up in the functions enclosing `state`, threading an
+    /// upvalue through every function in between.
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(state - 1, name) {
+            return Some(self.add_upvalue(state, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.states[state].upvalues;
+
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UINT8_COUNT {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let function = self.states[state].function;
+        let upvalues = &mut self.states[state].upvalues;
+        upvalues.push(upvalue);
+
+        unsafe {
+            (*function).upvalue_count = upvalues.len();
+        }
+
+        (upvalues.len() - 1) as u8
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let name = self.vm.copy_string(name);
+        self.make_constant(name.into())
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_with_operand(Opcode::OpDefineGlobal, global);
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let value: f64 = self
+            .previous_token()
+            .lexeme
+            .parse()
+            .expect("The scanner only produces valid number literals");
+        self.emit_constant(Value::Number(value))
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        let lexeme = self.previous_token().lexeme;
+
+        // Trim the leading and trailing quotation marks
+        let string = self.vm.copy_string(&lexeme[1..lexeme.len() - 1]);
+        self.emit_constant(string.into())
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let paren = self.previous.span();
+        let arg_count = self.argument_list();
+        self.emit_bytes_at(Opcode::OpCall.into(), arg_count, paren);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous_token().lexeme);
+        let name_span = self.previous.span();
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_with_operand_at(Opcode::OpSetProperty, name, name_span);
+        } else if self.matches(TokenType::LeftParen) {
+            // Calling a method directly skips creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_with_operand_at(Opcode::OpInvoke, name, name_span);
+            self.emit_byte_at(arg_count, name_span);
+        } else {
+            self.emit_with_operand(Opcode::OpGetProperty, name);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count = 0usize;
+
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count.min(u8::MAX as usize) as u8
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(Opcode::OpJumpIfFalse);
+
+        self.emit_byte(Opcode::OpPop.into());
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump)
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(Opcode::OpJumpIfFalse);
+        let end_jump = self.emit_jump(Opcode::OpJump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(Opcode::OpPop.into());
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump)
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous_token().lexeme;
+        self.named_variable(name, can_assign)
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let current = self.states.len() - 1;
+        let name_span = self.previous.span();
+
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, name) {
+            (Opcode::OpGetLocal, Opcode::OpSetLocal, slot as usize)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            (Opcode::OpGetUpvalue, Opcode::OpSetUpvalue, index as usize)
+        } else {
+            (
+                Opcode::OpGetGlobal,
+                Opcode::OpSetGlobal,
+                self.identifier_constant(name),
+            )
+        };
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.expression();
+            self.emit_with_operand_at(set_op, arg, name_span);
+        } else {
+            self.emit_with_operand(get_op, arg);
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => (),
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.previous_token().lexeme);
+
+        self.named_variable("this", false);
+
+        if self.matches(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_with_operand(Opcode::OpSuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable("super", false);
+            self.emit_with_operand(Opcode::OpGetSuper, name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` can never be assigned to
+        self.variable(false)
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous_token().r#type {
+            TokenType::False => self.emit_byte(Opcode::OpFalse.into()),
+            TokenType::Nil => self.emit_byte(Opcode::OpNil.into()),
+            TokenType::True => self.emit_byte(Opcode::OpTrue.into()),
+            _ => unreachable!(),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.")
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator_type = self.previous_token().r#type;
+        let operator = self.previous.span();
+
+        // Compile the operand
+        self.parse_precedence(Precedence::Unary);
+
+        match operator_type {
+            TokenType::Bang => self.emit_byte_at(Opcode::OpNot.into(), operator),
+            TokenType::Minus => self.emit_byte_at(Opcode::OpNegate.into(), operator),
+            _ => unreachable!(),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator_type = self.previous_token().r#type;
+        let operator = self.previous.span();
+        let rule = Compiler::get_rule(operator_type);
+        self.parse_precedence(rule.precedence.next());
+
+        match operator_type {
+            TokenType::BangEqual => {
+                self.emit_bytes_at(Opcode::OpEqual.into(), Opcode::OpNot.into(), operator)
+            }
+            TokenType::EqualEqual => self.emit_byte_at(Opcode::OpEqual.into(), operator),
+            TokenType::Greater => self.emit_byte_at(Opcode::OpGreater.into(), operator),
+            TokenType::GreaterEqual => {
+                self.emit_bytes_at(Opcode::OpLess.into(), Opcode::OpNot.into(), operator)
+            }
+            TokenType::Less => self.emit_byte_at(Opcode::OpLess.into(), operator),
+            TokenType::LessEqual => {
+                self.emit_bytes_at(Opcode::OpGreater.into(), Opcode::OpNot.into(), operator)
+            }
+            TokenType::Plus => self.emit_byte_at(Opcode::OpAdd.into(), operator),
+            TokenType::Minus => self.emit_byte_at(Opcode::OpSubtract.into(), operator),
+            TokenType::Star => self.emit_byte_at(Opcode::OpMultiply.into(), operator),
+            TokenType::Slash => self.emit_byte_at(Opcode::OpDivide.into(), operator),
+            _ => unreachable!(),
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+
+        let prefix_rule = match self
+            .previous_type()
+            .and_then(|t| Compiler::get_rule(t).prefix)
+        {
+            Some(rule) => rule,
+            None => {
+                self.error("Expect expression.");
+                return;
+            }
+        };
+
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign);
+
+        while precedence <= self.current_precedence() {
+            self.advance();
+
+            if let Some(infix_rule) = self
+                .previous_type()
+                .and_then(|t| Compiler::get_rule(t).infix)
+            {
+                infix_rule(self, can_assign);
+            }
+        }
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    fn current_precedence(&self) -> Precedence {
+        match &self.current {
+            ScanResult::Normal(token) => Compiler::get_rule(token.r#type).precedence,
+            _ => Precedence::None,
+        }
+    }
+
+    fn get_rule(r#type: TokenType) -> ParseRule<'a> {
+        match r#type {
+            TokenType::LeftParen => ParseRule::new(
+                Some(Compiler::grouping),
+                Some(Compiler::call),
+                Precedence::Call,
+            ),
+            TokenType::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+            TokenType::Minus => ParseRule::new(
+                Some(Compiler::unary),
+                Some(Compiler::binary),
+                Precedence::Term,
+            ),
+            TokenType::Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+            TokenType::Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+            TokenType::Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+            TokenType::Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+            TokenType::BangEqual => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::Equality)
+            }
+            TokenType::EqualEqual => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::Equality)
+            }
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+            }
+            TokenType::Identifier => {
+                ParseRule::new(Some(Compiler::variable), None, Precedence::None)
+            }
+            TokenType::String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+            TokenType::Number => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+            TokenType::Super => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+            TokenType::This => ParseRule::new(Some(Compiler::this), None, Precedence::None),
+            TokenType::And => ParseRule::new(None, Some(Compiler::and), Precedence::And),
+            TokenType::Or => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
+            TokenType::False | TokenType::Nil | TokenType::True => {
+                ParseRule::new(Some(Compiler::literal), None, Precedence::None)
+            }
+            _ => ParseRule::new(None, None, Precedence::None),
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        self.report(Diagnostic::new(
+            message,
+            self.location(self.previous.span()),
+        ));
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        // Like rustc, point just past the last token rather than at the
+        // empty line the end of the input may be on
+        let span = match self.current {
+            ScanResult::EOF(_) => self.previous.span().end(&self.source),
+            _ => self.current.span(),
+        };
+
+        self.report(Diagnostic::new(message, self.location(span)));
+    }
+
+    fn location(&self, span: Span) -> Location {
+        Location::new(Rc::clone(&self.source), span)
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.had_error = true;
+        self.diagnostics.push(diagnostic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        let mut vm = VM::new();
+
+        match compile(source, &mut vm) {
+            Ok(_) => Vec::new(),
+            Err(diagnostics) => diagnostics,
+        }
+    }
+
+    #[test]
+    fn unrelated_errors_are_all_reported_once() {
+        let source = "var a = ;
+print 1 +;
+fun f( { return 1; }
+var b = 1 @ 2;
+a + b = 3;
+print \"fine\";
+";
+
+        let diagnostics = diagnostics(source);
+        let reported: Vec<(i32, &str)> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.location.span.line, diagnostic.message.as_str()))
+            .collect();
+
+        assert_eq!(
+            reported,
+            [
+                (1, "Expect expression."),
+                (2, "Expect expression."),
+                (3, "Expect parameter name."),
+                (4, "Unexpected character."),
+                (5, "Invalid assignment target."),
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps_past_sixteen_bits_are_errors() {
+        // Each statement compiles to three bytes, so the body needs a jump
+        // of about 90 KB
+        let body = "1;".repeat(30_000);
+
+        let messages = |source: String| -> Vec<String> {
+            diagnostics(&source)
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect()
+        };
+
+        assert_eq!(
+            messages(format!("if (true) {{ {} }}", body)),
+            ["Too much code to jump over."]
+        );
+        assert_eq!(
+            messages(format!("while (false) {{ {} }}", body)),
+            ["Loop body too large."]
+        );
+    }
+
+    #[test]
+    fn errors_at_the_end_point_after_the_last_token() {
+        let after_number = diagnostics("print 1\n\n");
+
+        assert_eq!(after_number.len(), 1);
+        assert_eq!(after_number[0].message, "Expect ';' after value.");
+        assert_eq!(after_number[0].location.span, Span::new(7, 0, 1, 8));
+
+        let after_string = diagnostics("var s = \"one\ntwo\"\n");
+
+        assert_eq!(after_string.len(), 1);
+        assert_eq!(after_string[0].location.span, Span::new(17, 0, 2, 5));
+    }
+
+    #[test]
+    fn redeclarations_point_back_at_the_declaration() {
+        let diagnostics = diagnostics("{\n  var a = 1;\n  var a = 2;\n}\n");
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].location.span, Span::new(21, 1, 3, 7));
+        assert_eq!(diagnostics[0].notes.len(), 1);
+        assert_eq!(diagnostics[0].notes[0].message, "variable declared here");
+        assert_eq!(
+            diagnostics[0].notes[0]
+                .location
+                .as_ref()
+                .map(|location| location.span),
+            Some(Span::new(8, 1, 2, 7))
+        );
+    }
 }
@@ -1,5 +1,6 @@
-use crate::chunk::{Chunk, Opcode};
-use crate::value;
+use crate::chunk::{Chunk, Opcode, Operands};
+use crate::object::ObjFunction;
+use crate::value::{self, Value};
 
 pub(crate) struct Disassembler<'a> {
     pub(crate) chunk: &'a Chunk,
@@ -16,34 +17,60 @@ impl<'a> Disassembler<'a> {
         }
     }
 
+    pub(crate) fn new_at(chunk: &'a Chunk, name: &'a str, offset: usize) -> Self {
+        Self {
+            chunk,
+            name,
+            offset,
+        }
+    }
+
     pub(crate) fn disassemble_chunk(&mut self) {
-        println!("== {} ==", self.name);
+        println!(
+            "== {} == ({} constants, {} duplicates saved)",
+            self.name,
+            self.chunk.constants.len(),
+            self.chunk.constants_reused
+        );
 
         while self.offset < self.chunk.code.len() {
-            self.disassemble_instruction(self.chunk.code[self.offset].into())
+            self.disassemble_instruction()
         }
     }
 
-    pub(crate) fn disassemble_instruction(&mut self, instruction: Opcode) {
+    pub(crate) fn disassemble_instruction(&mut self) {
         print!("{:04} ", self.offset);
 
-        let line = Chunk::get_line(self.offset, &self.chunk.lines);
+        let line = Chunk::get_span(self.offset, &self.chunk.positions).line;
 
-        if self.offset > 0 && line == Chunk::get_line(self.offset - 1, &self.chunk.lines) {
+        if self.offset > 0 && line == Chunk::get_span(self.offset - 1, &self.chunk.positions).line {
             print!("   | ");
         } else {
             print!("{:4} ", line)
         }
 
-        match instruction {
-            Opcode::OpReturn => self.simple_instruction("OP_RETURN"),
-            Opcode::OpConstant => self.constant_instruction("OP_CONSTANT"),
-            Opcode::OpConstantLong => self.constant_instruction("OP_CONSTANT_LONG"),
-            Opcode::OpNegate => self.simple_instruction("OP_NEGATE"),
-            Opcode::OpAdd => self.simple_instruction("OP_ADD"),
-            Opcode::OpSubtract => self.simple_instruction("OP_SUBTRACT"),
-            Opcode::OpMultiply => self.simple_instruction("OP_MULTIPLY"),
-            Opcode::OpDivide => self.simple_instruction("OP_DIVIDE"),
+        let opcode = match Opcode::try_from(self.chunk.code[self.offset]) {
+            Ok(opcode) => opcode,
+            Err(byte) => {
+                println!("Unknown opcode {}", byte);
+                self.offset += 1;
+                return;
+            }
+        };
+
+        let name = opcode.name();
+
+        match opcode.operands() {
+            Operands::None => self.simple_instruction(name),
+            Operands::Byte => self.byte_instruction(name),
+            Operands::Constant => self.constant_instruction(name, 1),
+            Operands::ConstantLong => self.constant_instruction(name, 3),
+            Operands::Jump => self.jump_instruction(name, 1),
+            Operands::Loop => self.jump_instruction(name, -1),
+            Operands::Closure => self.closure_instruction(name, 1),
+            Operands::ClosureLong => self.closure_instruction(name, 3),
+            Operands::Invoke => self.invoke_instruction(name, 1),
+            Operands::InvokeLong => self.invoke_instruction(name, 3),
         }
     }
 
@@ -52,13 +79,77 @@ impl<'a> Disassembler<'a> {
         self.offset += 1;
     }
 
-    fn constant_instruction(&mut self, name: &str) {
-        let constant_offset: u8 = self.chunk.code[self.offset + 1].into();
+    fn byte_instruction(&mut self, name: &str) {
+        let slot = self.chunk.code[self.offset + 1];
+        println!("{:<-16} {:4}", name, slot);
+        self.offset += 2;
+    }
+
+    fn jump_instruction(&mut self, name: &str, sign: i64) {
+        let jump = u16::from_be_bytes([
+            self.chunk.code[self.offset + 1],
+            self.chunk.code[self.offset + 2],
+        ]);
+        let target = self.offset as i64 + 3 + sign * jump as i64;
+
+        println!("{:<-16} {:4} -> {}", name, self.offset, target);
+        self.offset += 3;
+    }
+
+    fn closure_instruction(&mut self, name: &str, width: usize) {
+        let constant = self.read_index(width);
+        let value = self.chunk.constants[constant];
+
+        print!("{:<-16} {:4} ", name, constant);
+        value::print_value(value);
+        println!();
+        self.offset += 1 + width;
 
-        print!("{:<-16} {:4} '", name, constant_offset);
-        value::print_value(self.chunk.constants[constant_offset as usize]);
+        let upvalue_count = match value {
+            Value::Obj(function) => unsafe { (*(function as *mut ObjFunction)).upvalue_count },
+            _ => 0,
+        };
+
+        for _ in 0..upvalue_count {
+            let is_local = self.chunk.code[self.offset];
+            let index = self.chunk.code[self.offset + 1];
+
+            println!(
+                "{:04}      |                     {} {}",
+                self.offset,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            );
+            self.offset += 2;
+        }
+    }
+
+    fn invoke_instruction(&mut self, name: &str, width: usize) {
+        let constant = self.read_index(width);
+        let arg_count = self.chunk.code[self.offset + 1 + width];
+
+        print!("{:<-16} ({} args) {:4} '", name, arg_count, constant);
+        value::print_value(self.chunk.constants[constant]);
         println!("'");
 
-        self.offset += 2;
+        self.offset += 2 + width;
+    }
+
+    fn constant_instruction(&mut self, name: &str, width: usize) {
+        let constant = self.read_index(width);
+
+        print!("{:<-16} {:4} '", name, constant);
+        value::print_value(self.chunk.constants[constant]);
+        println!("'");
+
+        self.offset += 1 + width;
+    }
+
+    /// Reads the little-endian constant index of `width` bytes following the
+    /// opcode.
+    fn read_index(&self, width: usize) -> usize {
+        let mut index = [0u8; 4];
+        index[..width].copy_from_slice(&self.chunk.code[self.offset + 1..self.offset + 1 + width]);
+        u32::from_le_bytes(index) as usize
     }
 }
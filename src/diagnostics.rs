@@ -0,0 +1,348 @@
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// Where a diagnostic points: a token's bytes in the source, along with
+/// the line and column it starts at, both counted from 1.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct Span {
+    /// Byte offset of the first character
+    pub(crate) start: usize,
+    /// In bytes, zero for the end of the source
+    pub(crate) len: usize,
+    pub(crate) line: i32,
+    pub(crate) column: i32,
+}
+
+impl Span {
+    pub(crate) fn new(start: usize, len: usize, line: i32, column: i32) -> Self {
+        Self {
+            start,
+            len,
+            line,
+            column,
+        }
+    }
+
+    /// The empty span just past the end of `self`, which is in `source`.
+    pub(crate) fn end(&self, source: &str) -> Span {
+        let text = &source[self.start..self.start + self.len];
+        let (line, column) = match text.rfind('\n') {
+            Some(newline) => (
+                self.line + text.matches('\n').count() as i32,
+                text[newline + 1..].chars().count() as i32 + 1,
+            ),
+            None => (self.line, self.column + text.chars().count() as i32),
+        };
+
+        Span::new(self.start + self.len, 0, line, column)
+    }
+
+    /// Splits the line `self` starts on into the text before the span and
+    /// the part of the span on that line, so multi-line strings only
+    /// underline their first line.
+    fn split_line<'a>(&self, source: &'a str) -> (&'a str, &'a str, &'a str) {
+        let start = self.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let end = (start + self.len).min(line_end);
+
+        let text = &source[line_start..line_end];
+        let text = text.strip_suffix('\r').unwrap_or(text);
+
+        (text, &source[line_start..start], &source[start..end])
+    }
+}
+
+/// A span along with the source it points into. Every line typed into the
+/// REPL is compiled on its own, so a span alone doesn't say which one.
+#[derive(Clone, Debug)]
+pub(crate) struct Location {
+    pub(crate) source: Rc<str>,
+    pub(crate) span: Span,
+}
+
+impl Location {
+    pub(crate) fn new(source: Rc<str>, span: Span) -> Self {
+        Self { source, span }
+    }
+}
+
+/// Extra context for a diagnostic, optionally pointing somewhere else in the
+/// source, like where a clashing variable was declared.
+pub(crate) struct Note {
+    pub(crate) message: String,
+    pub(crate) location: Option<Location>,
+}
+
+pub(crate) struct Diagnostic {
+    pub(crate) message: String,
+    pub(crate) location: Location,
+    pub(crate) notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    pub(crate) fn new(message: impl Into<String>, location: Location) -> Self {
+        Self {
+            message: message.into(),
+            location,
+            notes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_note(
+        mut self,
+        message: impl Into<String>,
+        location: Option<Location>,
+    ) -> Self {
+        self.notes.push(Note {
+            message: message.into(),
+            location,
+        });
+        self
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum ErrorFormat {
+    /// Source snippets with the span underlined, like rustc
+    Human,
+    /// One JSON object per line, for editors and other tools
+    Json,
+}
+
+/// Writes diagnostics to stderr in the chosen format.
+pub(crate) struct Emitter {
+    /// The name diagnostics give the source they point into
+    pub(crate) file: String,
+    pub(crate) format: ErrorFormat,
+}
+
+impl Emitter {
+    pub(crate) fn new() -> Self {
+        Self {
+            file: String::from("<repl>"),
+            format: ErrorFormat::Human,
+        }
+    }
+
+    pub(crate) fn emit(&self, diagnostic: &Diagnostic) {
+        eprint!("{}", self.render(diagnostic));
+    }
+
+    pub(crate) fn render(&self, diagnostic: &Diagnostic) -> String {
+        match self.format {
+            ErrorFormat::Human => self.render_human(diagnostic),
+            ErrorFormat::Json => self.render_json(diagnostic),
+        }
+    }
+
+    fn render_human(&self, diagnostic: &Diagnostic) -> String {
+        // Every gutter lines up with the widest line number shown
+        let width = diagnostic
+            .notes
+            .iter()
+            .filter_map(|note| note.location.as_ref())
+            .chain([&diagnostic.location])
+            .map(|location| location.span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+
+        let mut out = String::new();
+        writeln!(out, "error: {}", diagnostic.message).unwrap();
+        self.render_snippet(&mut out, &diagnostic.location, width);
+
+        for note in &diagnostic.notes {
+            match &note.location {
+                Some(location) => {
+                    writeln!(out, "note: {}", note.message).unwrap();
+                    self.render_snippet(&mut out, location, width);
+                }
+                None => writeln!(out, "{:width$} = note: {}", "", note.message).unwrap(),
+            }
+        }
+
+        out.push('\n');
+        out
+    }
+
+    fn render_snippet(&self, out: &mut String, location: &Location, width: usize) {
+        let span = location.span;
+        let (text, before, underlined) = span.split_line(&location.source);
+
+        // Tabs are kept in the padding so the carets line up with the text
+        let padding: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(
+            out,
+            "{:width$}--> {}:{}:{}",
+            "", self.file, span.line, span.column
+        )
+        .unwrap();
+        writeln!(out, "{:width$} |", "").unwrap();
+        writeln!(out, "{:>width$} | {}", span.line, text).unwrap();
+        writeln!(
+            out,
+            "{:width$} | {}{}",
+            "",
+            padding,
+            "^".repeat(underlined.chars().count().max(1))
+        )
+        .unwrap();
+    }
+
+    fn render_json(&self, diagnostic: &Diagnostic) -> String {
+        let mut out = String::new();
+        write!(
+            out,
+            "{{\"level\":\"error\",\"message\":{},\"file\":{},{}",
+            json_string(&diagnostic.message),
+            json_string(&self.file),
+            json_span(&diagnostic.location)
+        )
+        .unwrap();
+
+        out.push_str(",\"notes\":[");
+        for (i, note) in diagnostic.notes.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+
+            write!(out, "{{\"message\":{}", json_string(&note.message)).unwrap();
+            if let Some(location) = &note.location {
+                write!(out, ",{}", json_span(location)).unwrap();
+            }
+            out.push('}');
+        }
+        out.push_str("]}\n");
+
+        out
+    }
+}
+
+/// The length is in characters, like the column, and stops at the end of
+/// the line.
+fn json_span(location: &Location) -> String {
+    let (_, _, underlined) = location.span.split_line(&location.source);
+
+    format!(
+        "\"line\":{},\"column\":{},\"length\":{}",
+        location.span.line,
+        location.span.column,
+        underlined.chars().count()
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emitter(format: ErrorFormat) -> Emitter {
+        Emitter {
+            file: String::from("test.lox"),
+            format,
+        }
+    }
+
+    /// The location of `len` bytes at `line:column` in `source`.
+    fn span_at(source: &str, line: i32, column: i32, len: usize) -> Location {
+        let text = source
+            .split_inclusive('\n')
+            .nth((line - 1) as usize)
+            .unwrap();
+        let line_start = text.as_ptr() as usize - source.as_ptr() as usize;
+        let (offset, _) = text.char_indices().nth((column - 1) as usize).unwrap();
+
+        Location::new(
+            Rc::from(source),
+            Span::new(line_start + offset, len, line, column),
+        )
+    }
+
+    #[test]
+    fn human_output_underlines_the_span() {
+        let source = "var a = 1;\n{\n\tvar a = \"two\";\n}\n";
+        let diagnostic = Diagnostic::new(
+            "Already a variable with this name in this scope.",
+            span_at(source, 3, 6, 1),
+        )
+        .with_note("variable declared here", Some(span_at(source, 1, 5, 1)))
+        .with_note("shadowing is only allowed in a nested scope", None);
+
+        assert_eq!(
+            emitter(ErrorFormat::Human).render(&diagnostic),
+            "error: Already a variable with this name in this scope.
+ --> test.lox:3:6
+  |
+3 | \tvar a = \"two\";
+  | \t    ^
+note: variable declared here
+ --> test.lox:1:5
+  |
+1 | var a = 1;
+  |     ^
+  = note: shadowing is only allowed in a nested scope
+
+"
+        );
+    }
+
+    #[test]
+    fn only_the_first_line_of_a_span_is_underlined() {
+        let source = "print \"héllo\nworld\";";
+        let diagnostic = Diagnostic::new(
+            "Unterminated string.",
+            span_at(source, 1, 7, "\"héllo\nworld\"".len()),
+        );
+
+        assert_eq!(
+            emitter(ErrorFormat::Human).render(&diagnostic),
+            "error: Unterminated string.
+ --> test.lox:1:7
+  |
+1 | print \"héllo
+  |       ^^^^^^
+
+"
+        );
+    }
+
+    #[test]
+    fn json_output_is_one_escaped_line() {
+        let source = "fun f() {\n  print x;\n}\nf();\n";
+        let diagnostic = Diagnostic::new("Undefined variable 'x'.", span_at(source, 2, 9, 1))
+            .with_note("in \"f\"()", None)
+            .with_note("in script", Some(span_at(source, 4, 1, 1)));
+
+        assert_eq!(
+            emitter(ErrorFormat::Json).render(&diagnostic),
+            "{\"level\":\"error\",\"message\":\"Undefined variable 'x'.\",\"file\":\"test.lox\",\
+             \"line\":2,\"column\":9,\"length\":1,\"notes\":[{\"message\":\"in \\\"f\\\"()\"},\
+             {\"message\":\"in script\",\"line\":4,\"column\":1,\"length\":1}]}\n"
+        );
+    }
+}
@@ -1,29 +1,41 @@
 mod chunk;
 mod compiler;
 mod debug;
+mod diagnostics;
+mod memory;
+mod native;
+mod object;
 mod scanner;
+mod table;
 mod value;
 mod vm;
 
 use std::env;
-use std::process;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::process;
 
-use chunk::{Chunk, Opcode};
-use debug::Disassembler;
+use diagnostics::ErrorFormat;
 use vm::{InterpretResult, VM};
 
 fn repl(vm: &mut VM) {
     let mut buf = String::with_capacity(1024);
 
-    print!("> ");
-    io::stdout().flush().ok().expect("Failed to flush stdout");
-    while let Ok(_) = io::stdin().read_line(&mut buf) {
-        vm.interpret(&buf);
+    loop {
         print!("> ");
-	io::stdout().flush().ok().expect("Failed to flush stdout");
+        io::stdout().flush().expect("Failed to flush stdout");
+
+        buf.clear();
+        match io::stdin().read_line(&mut buf) {
+            Ok(0) | Err(_) => {
+                println!();
+                break;
+            }
+            Ok(_) => {
+                vm.interpret(&buf);
+            }
+        }
     }
 }
 
@@ -31,23 +43,61 @@ fn run_file(file_path: &str, vm: &mut VM) {
     let source =
         fs::read_to_string(file_path).unwrap_or_else(|_| panic!("Failed to read {}", file_path));
 
+    vm.emitter.file = file_path.to_string();
+
     match vm.interpret(&source) {
-	InterpretResult::InterpretCompileError => process::exit(65),
-	InterpretResult::InterpretRuntimeError => process::exit(70),
-	_ => panic!(),
+        InterpretResult::InterpretCompileError => process::exit(65),
+        InterpretResult::InterpretRuntimeError => process::exit(70),
+        InterpretResult::InterpretOk => (),
     }
+}
 
+const FLAGS: &[&str] = &["--trace", "--print-code", "--stress-gc", "--log-gc"];
 
+fn usage() -> ! {
+    eprintln!("Usage: clox [--trace] [--print-code] [--stress-gc] [--log-gc] [--error-format=human|json] [path]");
+    process::exit(64);
 }
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+
+    // A misspelt flag would otherwise be ignored, and a value passed without
+    // `=` taken for the script path
+    if let Some(flag) = flags
+        .iter()
+        .find(|flag| !FLAGS.contains(&flag.as_str()) && !flag.starts_with("--error-format="))
+    {
+        eprintln!("Unknown flag '{}'.", flag);
+        usage();
+    }
+
+    let mut vm = VM::new_with_debug(
+        flags.iter().any(|flag| flag == "--trace"),
+        flags.iter().any(|flag| flag == "--print-code"),
+    );
+    vm.stress_gc = flags.iter().any(|flag| flag == "--stress-gc");
+    vm.log_gc = flags.iter().any(|flag| flag == "--log-gc");
 
-    let mut dummy_chunk = Chunk::new();
-    let mut vm = VM::new(&dummy_chunk);
+    vm.emitter.format = match flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--error-format="))
+    {
+        None | Some("human") => ErrorFormat::Human,
+        Some("json") => ErrorFormat::Json,
+        Some(format) => {
+            eprintln!(
+                "Unknown error format '{}', expected 'human' or 'json'.",
+                format
+            );
+            process::exit(64);
+        }
+    };
 
     match args.len() {
-        1 => repl(&mut vm),
-        2 => run_file(&args[1], &mut vm),
-        _ => eprintln!("Usage: clox [path]"),
+        0 => repl(&mut vm),
+        1 => run_file(&args[0], &mut vm),
+        _ => usage(),
     }
 }
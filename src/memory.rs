@@ -0,0 +1,440 @@
+use std::mem;
+use std::ptr;
+
+use crate::chunk::{Chunk, PositionRun};
+use crate::object::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjString,
+    ObjType, ObjUpvalue,
+};
+use crate::table::{Entry, Table};
+use crate::value::Value;
+use crate::vm::VM;
+
+pub(crate) const GC_HEAP_GROW_FACTOR: usize = 2;
+
+impl VM {
+    /// Moves `object` onto the heap and links it into the VM's object list.
+    /// `T` must be one of the `#[repr(C)]` object types that start with an
+    /// `Obj` header.
+    ///
+    /// This may run a collection first, so anything the caller has allocated
+    /// but not yet made reachable from a root can be freed.
+    pub(crate) fn allocate_object<T>(&mut self, object: T) -> *mut T {
+        if self.stress_gc || self.bytes_allocated > self.next_gc {
+            self.collect_garbage();
+        }
+
+        let pointer = Box::into_raw(Box::new(object));
+        let obj = pointer as *mut Obj;
+
+        unsafe {
+            (*obj).next = self.objects;
+        }
+        self.objects = obj;
+
+        let size = object_size(obj);
+        self.bytes_allocated += size;
+
+        if self.log_gc {
+            println!("{:p} allocate {} for {:?}", obj, size, unsafe {
+                (*obj).r#type
+            });
+        }
+
+        pointer
+    }
+
+    /// Runs `update` on a table owned by a heap object, like an instance's
+    /// fields, counting any growth towards the next collection.
+    pub(crate) fn grow_table<T>(
+        &mut self,
+        table: *mut Table,
+        update: impl FnOnce(&mut Table) -> T,
+    ) -> T {
+        let table = unsafe { &mut *table };
+        let before = table_size(table);
+        let result = update(table);
+
+        self.bytes_allocated += table_size(table) - before;
+        result
+    }
+
+    /// Runs `write` on a function's chunk, counting any growth towards the
+    /// next collection.
+    pub(crate) fn grow_chunk<T>(
+        &mut self,
+        chunk: *mut Chunk,
+        write: impl FnOnce(&mut Chunk) -> T,
+    ) -> T {
+        let chunk = unsafe { &mut *chunk };
+        let before = chunk_size(chunk);
+        let result = write(chunk);
+
+        self.bytes_allocated += chunk_size(chunk) - before;
+        result
+    }
+
+    pub(crate) fn collect_garbage(&mut self) {
+        let before = self.bytes_allocated;
+
+        if self.log_gc {
+            println!("-- gc begin");
+        }
+
+        self.mark_roots();
+        self.trace_references();
+        // Interned strings are weak references, drop them before they dangle
+        self.strings.remove_white();
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+        if self.log_gc {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    fn mark_roots(&mut self) {
+        for slot in 0..self.stack.len() {
+            self.mark_value(self.stack[slot]);
+        }
+
+        for frame in 0..self.frames.len() {
+            self.mark_object(self.frames[frame].closure as *mut Obj);
+        }
+
+        let mut upvalue = self.open_upvalues;
+        while !upvalue.is_null() {
+            self.mark_object(upvalue as *mut Obj);
+            upvalue = unsafe { (*upvalue).next };
+        }
+
+        let globals = mem::take(&mut self.globals);
+        self.mark_table(&globals);
+        self.globals = globals;
+
+        for function in 0..self.compiler_roots.len() {
+            self.mark_object(self.compiler_roots[function] as *mut Obj);
+        }
+
+        self.mark_object(self.init_string as *mut Obj);
+    }
+
+    pub(crate) fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(object) = value {
+            self.mark_object(object);
+        }
+    }
+
+    pub(crate) fn mark_object(&mut self, object: *mut Obj) {
+        if object.is_null() {
+            return;
+        }
+
+        unsafe {
+            if (*object).is_marked {
+                return;
+            }
+
+            if self.log_gc {
+                print!("{:p} mark ", object);
+                crate::value::print_value(Value::Obj(object));
+                println!();
+            }
+
+            (*object).is_marked = true;
+        }
+
+        self.gray_stack.push(object);
+    }
+
+    fn mark_table(&mut self, table: &Table) {
+        for entry in table.entries.iter() {
+            self.mark_object(entry.key as *mut Obj);
+            self.mark_value(entry.value);
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(object) = self.gray_stack.pop() {
+            self.blacken_object(object);
+        }
+    }
+
+    fn blacken_object(&mut self, object: *mut Obj) {
+        if self.log_gc {
+            print!("{:p} blacken ", object);
+            crate::value::print_value(Value::Obj(object));
+            println!();
+        }
+
+        unsafe {
+            match (*object).r#type {
+                ObjType::BoundMethod => {
+                    let bound = object as *mut ObjBoundMethod;
+                    self.mark_value((*bound).receiver);
+                    self.mark_object((*bound).method as *mut Obj);
+                }
+                ObjType::Class => {
+                    let class = object as *mut ObjClass;
+                    self.mark_object((*class).name as *mut Obj);
+                    self.mark_table(&(*class).methods);
+                }
+                ObjType::Closure => {
+                    let closure = object as *mut ObjClosure;
+                    self.mark_object((*closure).function as *mut Obj);
+
+                    for upvalue in 0..(*closure).upvalues.len() {
+                        self.mark_object((&(*closure).upvalues)[upvalue] as *mut Obj);
+                    }
+                }
+                ObjType::Function => {
+                    let function = object as *mut ObjFunction;
+                    self.mark_object((*function).name as *mut Obj);
+
+                    for constant in 0..(*function).chunk.constants.len() {
+                        self.mark_value((&(*function).chunk.constants)[constant]);
+                    }
+                }
+                ObjType::Instance => {
+                    let instance = object as *mut ObjInstance;
+                    self.mark_object((*instance).class as *mut Obj);
+                    self.mark_table(&(*instance).fields);
+                }
+                ObjType::Native => self.mark_object((*(object as *mut ObjNative)).name as *mut Obj),
+                ObjType::Upvalue => {
+                    if let Some(closed) = (*(object as *mut ObjUpvalue)).closed {
+                        self.mark_value(closed);
+                    }
+                }
+                ObjType::String => (),
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut previous: *mut Obj = ptr::null_mut();
+        let mut object = self.objects;
+
+        while !object.is_null() {
+            unsafe {
+                if (*object).is_marked {
+                    (*object).is_marked = false;
+                    previous = object;
+                    object = (*object).next;
+                    continue;
+                }
+
+                let unreached = object;
+                object = (*object).next;
+
+                if previous.is_null() {
+                    self.objects = object;
+                } else {
+                    (*previous).next = object;
+                }
+
+                self.free_object(unreached);
+            }
+        }
+    }
+
+    pub(crate) fn free_objects(&mut self) {
+        let mut object = self.objects;
+
+        while !object.is_null() {
+            let next = unsafe { (*object).next };
+            self.free_object(object);
+            object = next;
+        }
+
+        self.objects = ptr::null_mut();
+        self.strings = Table::new();
+    }
+
+    fn free_object(&mut self, object: *mut Obj) {
+        self.bytes_allocated -= object_size(object);
+
+        if self.log_gc {
+            println!("{:p} free type {:?}", object, unsafe { (*object).r#type });
+        }
+
+        unsafe {
+            match (*object).r#type {
+                ObjType::BoundMethod => drop(Box::from_raw(object as *mut ObjBoundMethod)),
+                ObjType::Class => drop(Box::from_raw(object as *mut ObjClass)),
+                ObjType::Closure => drop(Box::from_raw(object as *mut ObjClosure)),
+                ObjType::Function => drop(Box::from_raw(object as *mut ObjFunction)),
+                ObjType::Instance => drop(Box::from_raw(object as *mut ObjInstance)),
+                ObjType::Native => drop(Box::from_raw(object as *mut ObjNative)),
+                ObjType::String => drop(Box::from_raw(object as *mut ObjString)),
+                ObjType::Upvalue => drop(Box::from_raw(object as *mut ObjUpvalue)),
+            }
+        }
+    }
+}
+
+/// The number of bytes `object` is accounted for, including the storage it
+/// owns. A chunk or table that grows after the object is created has to go
+/// through `grow_chunk` or `grow_table`, so the figure subtracted when the
+/// object is freed matches what was added.
+fn object_size(object: *mut Obj) -> usize {
+    unsafe {
+        match (*object).r#type {
+            ObjType::BoundMethod => mem::size_of::<ObjBoundMethod>(),
+            ObjType::Class => {
+                mem::size_of::<ObjClass>() + table_size(&(*(object as *mut ObjClass)).methods)
+            }
+            ObjType::Closure => {
+                mem::size_of::<ObjClosure>()
+                    + (*(object as *mut ObjClosure)).upvalues.capacity()
+                        * mem::size_of::<*mut ObjUpvalue>()
+            }
+            ObjType::Function => {
+                mem::size_of::<ObjFunction>() + chunk_size(&(*(object as *mut ObjFunction)).chunk)
+            }
+            ObjType::Instance => {
+                mem::size_of::<ObjInstance>() + table_size(&(*(object as *mut ObjInstance)).fields)
+            }
+            ObjType::Native => mem::size_of::<ObjNative>(),
+            ObjType::String => {
+                mem::size_of::<ObjString>() + (*(object as *mut ObjString)).chars.capacity()
+            }
+            ObjType::Upvalue => mem::size_of::<ObjUpvalue>(),
+        }
+    }
+}
+
+fn table_size(table: &Table) -> usize {
+    table.capacity() * mem::size_of::<Entry>()
+}
+
+/// The constant dedup map is left out since it's dropped once the function
+/// is compiled.
+fn chunk_size(chunk: &Chunk) -> usize {
+    chunk.code.capacity()
+        + chunk.constants.capacity() * mem::size_of::<Value>()
+        + chunk.positions.capacity() * mem::size_of::<PositionRun>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use crate::object;
+    use crate::table::Entry;
+    use crate::vm::VM;
+
+    fn object_count(vm: &VM) -> usize {
+        let mut count = 0;
+        let mut object = vm.objects;
+
+        while !object.is_null() {
+            count += 1;
+            object = unsafe { (*object).next };
+        }
+
+        count
+    }
+
+    fn is_interned(vm: &VM, chars: &str) -> bool {
+        vm.strings
+            .find_string(chars, object::hash_string(chars))
+            .is_some()
+    }
+
+    /// Collecting before every allocation frees anything a missing root
+    /// leaves unmarked, which the results would then read after it's gone.
+    #[test]
+    fn stress_collection_keeps_everything_reachable() {
+        let mut vm = VM::new();
+        vm.stress_gc = true;
+
+        let source = "
+class Shape {
+  init(name) { this.name = name; }
+  describe() { return \"a \" + this.name; }
+}
+class Square < Shape {
+  init(side) { super.init(\"square\"); this.side = side; }
+  describe() { return super.describe() + \" of \" + str(this.side); }
+}
+fun adder(n) {
+  fun add(m) { return n + m; }
+  return add;
+}
+var add = adder(40);
+var description = \"\";
+for (var i = 1; i <= 3; i = i + 1) {
+  var method = Square(i).describe;
+  description = description + method() + \";\";
+}
+var sum = add(2);
+";
+
+        assert_eq!(
+            vm.interpret_global(source, "description"),
+            "a square of 1;a square of 2;a square of 3;"
+        );
+        assert_eq!(vm.interpret_global("", "sum"), "42");
+    }
+
+    /// Instances with a few fields each are mostly field tables, which only
+    /// grow after the instance is allocated.
+    #[test]
+    fn growth_after_allocation_is_counted() {
+        let mut vm = VM::new();
+        let source = "
+class Point { init(x, y) { this.x = x; this.y = y; this.z = x + y; } }
+var points = nil;
+for (var i = 0; i < 100; i = i + 1) {
+  var point = Point(i, i);
+  point.next = points;
+  points = point;
+}
+var last = points.z;
+";
+
+        assert_eq!(vm.interpret_global(source, "last"), "198");
+
+        let mut counted = 0;
+        let mut object = vm.objects;
+        while !object.is_null() {
+            counted += super::object_size(object);
+            object = unsafe { (*object).next };
+        }
+
+        assert_eq!(vm.bytes_allocated, counted);
+        assert!(counted > 100 * 8 * mem::size_of::<Entry>());
+    }
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let mut vm = VM::new();
+        let source = "
+var kept = \"kept\";
+for (var i = 0; i < 100; i = i + 1) {
+  var garbage = \"garbage \" + str(i);
+}
+";
+
+        assert_eq!(vm.interpret_global(source, "kept"), "kept");
+        assert!(is_interned(&vm, "garbage 99"));
+
+        let before = (object_count(&vm), vm.bytes_allocated);
+        vm.collect_garbage();
+
+        assert!(object_count(&vm) < before.0);
+        assert!(vm.bytes_allocated < before.1);
+        assert!(!is_interned(&vm, "garbage 99"));
+        assert!(is_interned(&vm, "kept"));
+        assert_eq!(vm.interpret_global("", "kept"), "kept");
+    }
+}
@@ -0,0 +1,167 @@
+use std::io;
+use std::io::Write;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::object::ObjType;
+use crate::value::Value;
+use crate::vm::{RuntimeError, VM};
+
+impl VM {
+    /// Registers the standard library as globals.
+    pub(crate) fn define_natives(&mut self) {
+        self.define_native("clock", 0, clock);
+        self.define_native("sqrt", 1, sqrt);
+        self.define_native("floor", 1, floor);
+        self.define_native("abs", 1, abs);
+        self.define_native("str", 1, str);
+        self.define_native("num", 1, num);
+        self.define_native("len", 1, len);
+        self.define_native("input", 0, input);
+        self.define_native("exit", 1, exit);
+    }
+}
+
+fn number_argument(name: &str, value: Value) -> Result<f64, RuntimeError> {
+    match value {
+        Value::Number(n) => Ok(n),
+        _ => Err(RuntimeError::new(format!(
+            "Argument to '{}' must be a number.",
+            name
+        ))),
+    }
+}
+
+fn string_argument<'a>(name: &str, value: &'a Value) -> Result<&'a str, RuntimeError> {
+    if value.is_string() {
+        Ok(&value.as_string().chars)
+    } else {
+        Err(RuntimeError::new(format!(
+            "Argument to '{}' must be a string.",
+            name
+        )))
+    }
+}
+
+/// Seconds since the Unix epoch, only meaningful as a difference between two
+/// calls.
+fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| RuntimeError::new("System clock is set before the Unix epoch."))?;
+
+    Ok(Value::Number(elapsed.as_secs_f64()))
+}
+
+fn sqrt(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number_argument("sqrt", args[0])?.sqrt()))
+}
+
+fn floor(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number_argument("floor", args[0])?.floor()))
+}
+
+fn abs(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Number(number_argument("abs", args[0])?.abs()))
+}
+
+/// Formats any value the same way `print` does.
+fn str(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0].is_obj_type(ObjType::String) {
+        return Ok(args[0]);
+    }
+
+    Ok(vm.take_string(args[0].to_string()).into())
+}
+
+/// Parses a number out of a string, returning `nil` if it isn't one.
+fn num(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if let Value::Number(_) = args[0] {
+        return Ok(args[0]);
+    }
+
+    let chars = string_argument("num", &args[0])?;
+
+    Ok(chars.trim().parse().map_or(Value::Nil, Value::Number))
+}
+
+/// The number of characters in a string.
+fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let chars = string_argument("len", &args[0])?;
+    Ok(Value::Number(chars.chars().count() as f64))
+}
+
+/// Reads a line from stdin without its line ending, or `nil` at the end of
+/// input.
+fn input(vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let mut line = String::new();
+
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let trimmed = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(trimmed);
+            Ok(vm.take_string(line).into())
+        }
+        Err(error) => Err(RuntimeError::new(format!(
+            "Failed to read input: {}.",
+            error
+        ))),
+    }
+}
+
+fn exit(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let code = number_argument("exit", args[0])?;
+
+    // process::exit skips destructors, including the one flushing stdout
+    io::stdout().flush().expect("Failed to flush stdout");
+    process::exit(code as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::VM;
+
+    #[test]
+    fn natives_compute_results() {
+        let mut vm = VM::new();
+        let source = "
+var root = sqrt(16);
+var floored = floor(-2.5);
+var absolute = abs(-3);
+var text = str(1.5) + str(nil) + str(true);
+var parsed = num(\" 42 \") + num(1);
+var unparsable = num(\"forty two\");
+var length = len(\"héllo\");
+var elapsed = clock() - clock() <= 0;
+";
+
+        assert_eq!(vm.interpret_global(source, "root"), "4");
+        assert_eq!(vm.interpret_global("", "floored"), "-3");
+        assert_eq!(vm.interpret_global("", "absolute"), "3");
+        assert_eq!(vm.interpret_global("", "text"), "1.5niltrue");
+        assert_eq!(vm.interpret_global("", "parsed"), "43");
+        assert_eq!(vm.interpret_global("", "unparsable"), "nil");
+        assert_eq!(vm.interpret_global("", "length"), "5");
+        assert_eq!(vm.interpret_global("", "elapsed"), "true");
+        assert_eq!(
+            vm.interpret_global("var f = sqrt;", "f"),
+            "<native fn sqrt>"
+        );
+    }
+
+    #[test]
+    fn bad_native_calls_are_runtime_errors() {
+        let mut vm = VM::new();
+
+        for (source, message) in [
+            ("sqrt();", "Expected 1 arguments but got 0."),
+            ("sqrt(1, 2);", "Expected 1 arguments but got 2."),
+            ("sqrt(\"4\");", "Argument to 'sqrt' must be a number."),
+            ("len(4);", "Argument to 'len' must be a string."),
+            ("num(nil);", "Argument to 'num' must be a string."),
+        ] {
+            assert_eq!(vm.runtime_error_message(source), message, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::fmt;
+use std::ptr;
+
+use crate::chunk::Chunk;
+use crate::table::Table;
+use crate::value::Value;
+use crate::vm::{RuntimeError, VM};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum ObjType {
+    BoundMethod,
+    Class,
+    Closure,
+    Function,
+    Instance,
+    Native,
+    String,
+    Upvalue,
+}
+
+/// The header shared by every heap object. Concrete objects embed it as
+/// their first field so a `*mut Obj` can be cast back to the full object
+/// once its `r#type` has been checked.
+#[repr(C)]
+pub(crate) struct Obj {
+    pub(crate) r#type: ObjType,
+    pub(crate) is_marked: bool,
+    pub(crate) next: *mut Obj,
+}
+
+impl Obj {
+    pub(crate) fn new(r#type: ObjType) -> Self {
+        Self {
+            r#type,
+            is_marked: false,
+            next: ptr::null_mut(),
+        }
+    }
+}
+
+#[repr(C)]
+pub(crate) struct ObjString {
+    pub(crate) obj: Obj,
+    pub(crate) hash: u32,
+    pub(crate) chars: String,
+}
+
+#[repr(C)]
+pub(crate) struct ObjFunction {
+    pub(crate) obj: Obj,
+    pub(crate) arity: usize,
+    pub(crate) upvalue_count: usize,
+    pub(crate) chunk: Chunk,
+    /// Null for the top-level script
+    pub(crate) name: *mut ObjString,
+}
+
+#[repr(C)]
+pub(crate) struct ObjClosure {
+    pub(crate) obj: Obj,
+    pub(crate) function: *mut ObjFunction,
+    pub(crate) upvalues: Vec<*mut ObjUpvalue>,
+}
+
+#[repr(C)]
+pub(crate) struct ObjClass {
+    pub(crate) obj: Obj,
+    pub(crate) name: *mut ObjString,
+    pub(crate) methods: Table,
+}
+
+#[repr(C)]
+pub(crate) struct ObjInstance {
+    pub(crate) obj: Obj,
+    pub(crate) class: *mut ObjClass,
+    pub(crate) fields: Table,
+}
+
+/// A method accessed as a property, remembering the instance it was
+/// accessed on so `this` still refers to it when called later.
+#[repr(C)]
+pub(crate) struct ObjBoundMethod {
+    pub(crate) obj: Obj,
+    pub(crate) receiver: Value,
+    pub(crate) method: *mut ObjClosure,
+}
+
+/// The Rust side of a native function. It receives the call's arguments,
+/// already checked against the declared arity.
+pub(crate) type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+#[repr(C)]
+pub(crate) struct ObjNative {
+    pub(crate) obj: Obj,
+    pub(crate) name: *mut ObjString,
+    pub(crate) arity: usize,
+    pub(crate) function: NativeFn,
+}
+
+/// A variable captured by a closure. While the variable is still on the
+/// stack the upvalue is open and refers to its slot; once the variable goes
+/// out of scope its value is moved into `closed`.
+#[repr(C)]
+pub(crate) struct ObjUpvalue {
+    pub(crate) obj: Obj,
+    pub(crate) location: usize,
+    pub(crate) closed: Option<Value>,
+    /// The next open upvalue, in order of decreasing stack slot
+    pub(crate) next: *mut ObjUpvalue,
+}
+
+/// FNV-1a
+pub(crate) fn hash_string(chars: &str) -> u32 {
+    let mut hash = 2166136261u32;
+
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+
+    hash
+}
+
+impl VM {
+    pub(crate) fn new_function(&mut self) -> *mut ObjFunction {
+        self.allocate_object(ObjFunction {
+            obj: Obj::new(ObjType::Function),
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name: ptr::null_mut(),
+        })
+    }
+
+    pub(crate) fn new_closure(&mut self, function: *mut ObjFunction) -> *mut ObjClosure {
+        let upvalue_count = unsafe { (*function).upvalue_count };
+
+        self.allocate_object(ObjClosure {
+            obj: Obj::new(ObjType::Closure),
+            function,
+            upvalues: Vec::with_capacity(upvalue_count),
+        })
+    }
+
+    pub(crate) fn new_bound_method(
+        &mut self,
+        receiver: Value,
+        method: *mut ObjClosure,
+    ) -> *mut ObjBoundMethod {
+        self.allocate_object(ObjBoundMethod {
+            obj: Obj::new(ObjType::BoundMethod),
+            receiver,
+            method,
+        })
+    }
+
+    pub(crate) fn new_class(&mut self, name: *mut ObjString) -> *mut ObjClass {
+        self.allocate_object(ObjClass {
+            obj: Obj::new(ObjType::Class),
+            name,
+            methods: Table::new(),
+        })
+    }
+
+    pub(crate) fn new_instance(&mut self, class: *mut ObjClass) -> *mut ObjInstance {
+        self.allocate_object(ObjInstance {
+            obj: Obj::new(ObjType::Instance),
+            class,
+            fields: Table::new(),
+        })
+    }
+
+    pub(crate) fn new_native(
+        &mut self,
+        name: *mut ObjString,
+        arity: usize,
+        function: NativeFn,
+    ) -> *mut ObjNative {
+        self.allocate_object(ObjNative {
+            obj: Obj::new(ObjType::Native),
+            name,
+            arity,
+            function,
+        })
+    }
+
+    pub(crate) fn new_upvalue(&mut self, location: usize) -> *mut ObjUpvalue {
+        self.allocate_object(ObjUpvalue {
+            obj: Obj::new(ObjType::Upvalue),
+            location,
+            closed: None,
+            next: ptr::null_mut(),
+        })
+    }
+
+    fn allocate_string(&mut self, chars: String, hash: u32) -> *mut ObjString {
+        let string = self.allocate_object(ObjString {
+            obj: Obj::new(ObjType::String),
+            hash,
+            chars,
+        });
+
+        self.strings.set(string, Value::Nil);
+        string
+    }
+
+    /// Interns `chars`, copying them onto the heap only if an equal string
+    /// doesn't already exist.
+    pub(crate) fn copy_string(&mut self, chars: &str) -> *mut ObjString {
+        let hash = hash_string(chars);
+
+        if let Some(interned) = self.strings.find_string(chars, hash) {
+            return interned;
+        }
+
+        self.allocate_string(chars.to_string(), hash)
+    }
+
+    /// Like `copy_string`, but takes ownership of an already built string.
+    pub(crate) fn take_string(&mut self, chars: String) -> *mut ObjString {
+        let hash = hash_string(&chars);
+
+        if let Some(interned) = self.strings.find_string(&chars, hash) {
+            return interned;
+        }
+
+        self.allocate_string(chars, hash)
+    }
+}
+
+fn fmt_function(function: &ObjFunction, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if function.name.is_null() {
+        write!(f, "<script>")
+    } else {
+        write!(f, "<fn {}>", unsafe { &(*function.name).chars })
+    }
+}
+
+pub(crate) fn fmt_object(object: *mut Obj, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    unsafe {
+        match (*object).r#type {
+            ObjType::BoundMethod => {
+                fmt_function(&*(*(*(object as *mut ObjBoundMethod)).method).function, f)
+            }
+            ObjType::Class => write!(f, "{}", (*(*(object as *mut ObjClass)).name).chars),
+            ObjType::Closure => fmt_function(&*(*(object as *mut ObjClosure)).function, f),
+            ObjType::Function => fmt_function(&*(object as *mut ObjFunction), f),
+            ObjType::Instance => write!(
+                f,
+                "{} instance",
+                (*(*(*(object as *mut ObjInstance)).class).name).chars
+            ),
+            ObjType::Native => write!(
+                f,
+                "<native fn {}>",
+                (*(*(object as *mut ObjNative)).name).chars
+            ),
+            ObjType::String => write!(f, "{}", (*(object as *mut ObjString)).chars),
+            ObjType::Upvalue => write!(f, "upvalue"),
+        }
+    }
+}
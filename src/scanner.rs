@@ -1,6 +1,6 @@
-use std::iter::{Peekable, Take};
-use std::str::Chars;
+use crate::diagnostics::Span;
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub(crate) enum TokenType {
     // Single character tokens
     LeftParen,
@@ -49,385 +49,455 @@ pub(crate) enum TokenType {
     While,
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub(crate) enum ScanResult<'a> {
-    Normal(Token<Take<Peekable<Chars<'a>>>>),
+    Normal(Token<'a>),
     EOF(EOFToken),
     Error(ErrorToken<'a>),
 }
 
-pub(crate) struct Token<I>
-where
-    I: Iterator,
-{
-    pub(crate) r#type: TokenType,
-    lexeme: I,
-    pub(crate) length: usize,
-    pub(crate) line: i32,
+impl ScanResult<'_> {
+    /// Where diagnostics about this token point.
+    pub(crate) fn span(&self) -> Span {
+        match self {
+            ScanResult::Normal(token) => {
+                Span::new(token.start, token.lexeme.len(), token.line, token.column)
+            }
+            ScanResult::Error(token) => {
+                Span::new(token.start, token.lexeme.len(), token.line, token.column)
+            }
+            ScanResult::EOF(token) => Span::new(token.start, 0, token.line, token.column),
+        }
+    }
 }
 
-// impl<'a> Token<Chars<'a>> {
-//     // Investigate whether using slice_from_raw_parts(lexeme.as_str().as_ptr(), length)
-//     // improves memory usage and or speed, due to not having to allocate memory for String
-//     pub(crate) fn get_lexeme(&mut self) -> String {
-//         self.lexeme.clone().take(self.length).collect()
-//     }
-// }
-
-impl<'a> Token<Take<Peekable<Chars<'a>>>> {
-    pub(crate) fn get_lexeme(&mut self) -> String {
-        self.lexeme.clone().collect()
-    }
+/// A token borrows its lexeme straight from the source, so scanning never
+/// allocates.
+#[derive(Clone)]
+pub(crate) struct Token<'a> {
+    pub(crate) r#type: TokenType,
+    pub(crate) lexeme: &'a str,
+    /// Byte offset of the lexeme in the source
+    pub(crate) start: usize,
+    pub(crate) line: i32,
+    pub(crate) column: i32,
 }
 
+#[derive(Clone)]
 pub(crate) struct EOFToken {
+    /// The length of the source
+    pub(crate) start: usize,
     pub(crate) line: i32,
+    pub(crate) column: i32,
 }
 
 impl EOFToken {
-    pub(crate) fn new(line: i32) -> Self {
-        Self { line }
+    pub(crate) fn new(start: usize, line: i32, column: i32) -> Self {
+        Self {
+            start,
+            line,
+            column,
+        }
     }
 }
 
+/// Source that couldn't be scanned. The span covers the offending text,
+/// which is skipped so scanning can carry on after it.
+#[derive(Clone)]
 pub(crate) struct ErrorToken<'a> {
-    pub(crate) message: &'a str,
-}
-
-impl<'a> ErrorToken<'a> {
-    pub(crate) fn new(message: &'a str) -> Self {
-        Self { message }
-    }
+    pub(crate) message: &'static str,
+    pub(crate) lexeme: &'a str,
+    /// Byte offset of the lexeme in the source
+    pub(crate) start: usize,
+    pub(crate) line: i32,
+    pub(crate) column: i32,
 }
 
 pub(crate) struct Scanner<'a> {
-    pub(crate) start: Peekable<Chars<'a>>,
-    pub(crate) current: Peekable<Chars<'a>>,
-    pub(crate) current_index_in_lexeme: usize,
+    source: &'a str,
+    /// Byte offset of the first character of the token being scanned
+    start: usize,
+    /// Byte offset of the next character
+    current: usize,
     pub(crate) line: i32,
+    /// Column of the next character, counted in characters from 1
+    column: i32,
+    start_line: i32,
+    start_column: i32,
 }
 
 impl<'a> Scanner<'a> {
     pub(crate) fn new(source: &'a str) -> Self {
         Self {
-            start: source.chars().peekable(),
-            current: source.chars().peekable(),
-            current_index_in_lexeme: 0,
+            source,
+            start: 0,
+            current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
-    pub(crate) fn make_token(&self, r#type: TokenType) -> Token<Take<Peekable<Chars<'a>>>> {
-        Token {
-            r#type,
-            lexeme: self.get_lexeme(),
-            length: self.current_index_in_lexeme,
-            line: self.line,
+    pub(crate) fn scan_token(&mut self) -> ScanResult<'a> {
+        self.skip_whitespace();
+
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
+
+        let c = match self.advance() {
+            Some(c) => c,
+            None => return ScanResult::EOF(EOFToken::new(self.current, self.line, self.column)),
+        };
+
+        match c {
+            '(' => self.make_token(TokenType::LeftParen),
+            ')' => self.make_token(TokenType::RightParen),
+            '{' => self.make_token(TokenType::LeftBrace),
+            '}' => self.make_token(TokenType::RightBrace),
+            ';' => self.make_token(TokenType::Semicolon),
+            ',' => self.make_token(TokenType::Comma),
+            '.' => self.make_token(TokenType::Dot),
+            '-' => self.make_token(TokenType::Minus),
+            '+' => self.make_token(TokenType::Plus),
+            '/' => self.make_token(TokenType::Slash),
+            '*' => self.make_token(TokenType::Star),
+            '!' => self.make_token_if('=', TokenType::BangEqual, TokenType::Bang),
+            '=' => self.make_token_if('=', TokenType::EqualEqual, TokenType::Equal),
+            '<' => self.make_token_if('=', TokenType::LessEqual, TokenType::Less),
+            '>' => self.make_token_if('=', TokenType::GreaterEqual, TokenType::Greater),
+            '"' => self.string(),
+            c if is_alpha(c) => self.identifier(),
+            c if c.is_ascii_digit() => self.number(),
+            _ => self.error_token("Unexpected character."),
         }
     }
 
-    pub(crate) fn advance(&mut self) -> Option<char> {
-        let mut binding = self.current.clone();
-        let current = binding.peek();
+    fn make_token(&self, r#type: TokenType) -> ScanResult<'a> {
+        ScanResult::Normal(Token {
+            r#type,
+            lexeme: self.lexeme(),
+            start: self.start,
+            // Where the token begins, even if it spans lines
+            line: self.start_line,
+            column: self.start_column,
+        })
+    }
 
-        if let Some(c) = self.current.next() {
-            if !c.is_whitespace() {
-                self.current_index_in_lexeme += 1;
-            }
-        }
+    fn error_token(&self, message: &'static str) -> ScanResult<'a> {
+        ScanResult::Error(ErrorToken {
+            message,
+            lexeme: self.lexeme(),
+            start: self.start,
+            line: self.start_line,
+            column: self.start_column,
+        })
+    }
 
-        match current {
-            Some(c_ref) => Some(*c_ref),
-            None => None,
+    /// Makes a `matched` token if the next character is `expected`, which
+    /// then becomes part of the lexeme, or an `unmatched` one otherwise.
+    fn make_token_if(
+        &mut self,
+        expected: char,
+        matched: TokenType,
+        unmatched: TokenType,
+    ) -> ScanResult<'a> {
+        if self.matches(expected) {
+            self.make_token(matched)
+        } else {
+            self.make_token(unmatched)
         }
     }
 
-    pub(crate) fn scan_token(&mut self) -> ScanResult<'a> {
-        self.skip_whitespace();
-
-        self.start = self.current.clone();
-        // let mut binding = self.current.clone();
-
-        // if let Some(test) = binding.peek() {
-        //     println!("scan token test: {}", test);
-	// }
+    fn lexeme(&self) -> &'a str {
+        &self.source[self.start..self.current]
+    }
 
-        self.current_index_in_lexeme = 0usize;
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.current += c.len_utf8();
 
-        if self.is_at_end() {
-            ScanResult::EOF(EOFToken::new(self.line))
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
         } else {
-            let c = self.advance();
-            // println!("Scan token...");
-
-            match c {
-                Some('(') => ScanResult::Normal(self.make_token(TokenType::LeftParen)),
-                Some(')') => ScanResult::Normal(self.make_token(TokenType::RightParen)),
-                Some('{') => ScanResult::Normal(self.make_token(TokenType::LeftBrace)),
-                Some('}') => ScanResult::Normal(self.make_token(TokenType::RightBrace)),
-                Some(';') => ScanResult::Normal(self.make_token(TokenType::Semicolon)),
-                Some(',') => ScanResult::Normal(self.make_token(TokenType::Comma)),
-                Some('.') => ScanResult::Normal(self.make_token(TokenType::Dot)),
-                Some('-') => ScanResult::Normal(self.make_token(TokenType::Minus)),
-                Some('+') => ScanResult::Normal(self.make_token(TokenType::Plus)),
-                Some('/') => ScanResult::Normal(self.make_token(TokenType::Slash)),
-                Some('*') => ScanResult::Normal(self.make_token(TokenType::Star)),
-                Some('!') => {
-                    let token = if self.matches('=') {
-                        TokenType::BangEqual
-                    } else {
-                        TokenType::Bang
-                    };
-                    ScanResult::Normal(self.make_token(token))
-                }
-                Some('=') => {
-                    let token = if self.matches('=') {
-                        TokenType::EqualEqual
-                    } else {
-                        TokenType::Equal
-                    };
-                    ScanResult::Normal(self.make_token(token))
-                }
-                Some('<') => {
-                    let token = if self.matches('=') {
-                        TokenType::LessEqual
-                    } else {
-                        TokenType::Less
-                    };
-                    ScanResult::Normal(self.make_token(token))
-                }
-                Some('>') => {
-                    let token = if self.matches('=') {
-                        TokenType::GreaterEqual
-                    } else {
-                        TokenType::Greater
-                    };
-                    ScanResult::Normal(self.make_token(token))
-                }
-                Some('"') => self.string(),
-                Some(ident_or_digit) => {
-                    if self.is_alpha(ident_or_digit) {
-                        self.identifier()
-                    } else if ident_or_digit.is_digit(10) {
-                        self.number()
-                    } else {
-                        ScanResult::Error(ErrorToken::new("Unexpected character"))
-                    }
-                }
-                None => ScanResult::Error(ErrorToken::new("Unexpected character")),
-            }
+            self.column += 1;
         }
+
+        Some(c)
     }
 
     fn matches(&mut self, expected: char) -> bool {
-        let mut current = self.current.clone();
-
-        match current.peek() {
-            Some(c_ref) => {
-                if self.is_at_end() || *c_ref != expected {
-                    false
-                } else {
-                    self.current.next();
-                    self.current_index_in_lexeme += 1;
-                    true
-                }
-            }
-            _ => panic!("is_at_end() arm should have been true!"),
+        if self.peek() == Some(expected) {
+            self.advance();
+            true
+        } else {
+            false
         }
     }
 
-    fn get_lexeme(&self) -> Take<Peekable<Chars<'a>>> {
-        let test: String = self
-            .start
-            .clone()
-            .take(self.current_index_in_lexeme)
-            .collect();
-        // println!(
-        //     "lex from scanner get lexeme: {} {}",
-        //     test, self.current_index_in_lexeme
-        // );
-        self.start.clone().take(self.current_index_in_lexeme)
-    }
-
-    fn is_at_end(&mut self) -> bool {
-        self.current.peek().is_none()
+    fn peek(&self) -> Option<char> {
+        self.source[self.current..].chars().next()
     }
 
-    fn is_alpha(&self, c: char) -> bool {
-        c.is_alphabetic() || c == '_'
-    }
-
-    fn peek_next(&mut self) -> Option<char> {
-        self.current.by_ref().nth(1)
+    fn peek_next(&self) -> Option<char> {
+        let mut lookahead = self.source[self.current..].chars();
+        lookahead.next();
+        lookahead.next()
     }
 
     fn skip_whitespace(&mut self) {
         loop {
-            match self.current.peek() {
-                Some('\n') => {
-		    // println!("newline detected");
-                    self.line += 1;
-                    self.advance();
-                }
-                Some('/') => {
-                    if let Some('/') = self.peek_next() {
-                        while let Some(c_ref) = self.current.peek() {
-                            if *c_ref == '\n' {
-                                break;
-                            } else {
-                                self.advance();
-                            }
-                        }
-                    }
-                }
-                Some(c) => {
-                    if c.is_whitespace() {
+            match self.peek() {
+                Some('/') if self.peek_next() == Some('/') => {
+                    // A comment goes until the end of the line
+                    while self.peek().is_some_and(|c| c != '\n') {
                         self.advance();
-                    } else {
-                        // println!("In whitespace");
-                        return;
                     }
                 }
-                None => return,
+                Some(c) if c.is_whitespace() => {
+                    self.advance();
+                }
+                _ => return,
             }
         }
     }
 
     fn string(&mut self) -> ScanResult<'a> {
-        if let Some(c_ref) = self.current.peek() {
-	    let mut binding = *c_ref;
-
-	    while binding != '"' {
-		if binding == '\n' {
-		    self.line += 1;
-		}
-
-		match self.advance() {
-		    Some(next) => {binding = next},
-		    None => break,
-		}
-	    }
+        while self.peek().is_some_and(|c| c != '"') {
+            self.advance();
         }
 
-        if self.is_at_end() {
-            ScanResult::Error(ErrorToken::new("Unterminated string"))
-        } else {
-            self.advance();
-            ScanResult::Normal(self.make_token(TokenType::String))
+        // The closing quote
+        if self.advance().is_none() {
+            return self.error_token("Unterminated string.");
         }
+
+        self.make_token(TokenType::String)
     }
 
     fn number(&mut self) -> ScanResult<'a> {
-        if let Some(c_ref) = self.current.peek() {
-            let mut binding = *c_ref;
+        self.digits();
 
-            while binding.is_digit(10) {
-                match self.advance() {
-                    Some(next) => binding = next,
-                    None => break,
-                }
-            }
+        // A trailing "." isn't part of the number
+        if self.peek() == Some('.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+            self.digits();
         }
 
-        if let Some('.') = self.current.peek() {
-            if let Some(c) = self.peek_next() {
-                if c.is_digit(10) {
-                    self.advance();
+        self.make_token(TokenType::Number)
+    }
 
-                    if let Some(c_ref) = self.current.peek() {
-                        let mut binding = *c_ref;
+    fn digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+        }
+    }
 
-                        while binding.is_digit(10) {
-                            match self.advance() {
-                                Some(next) => binding = next,
-                                None => break,
-                            }
-                        }
-                    }
-                }
-            }
+    fn identifier(&mut self) -> ScanResult<'a> {
+        while self
+            .peek()
+            .is_some_and(|c| is_alpha(c) || c.is_ascii_digit())
+        {
+            self.advance();
         }
 
-        ScanResult::Normal(self.make_token(TokenType::Number))
+        self.make_token(self.identifier_type())
     }
 
-    fn identifier(&mut self) -> ScanResult<'a> {
-        // println!("In identifier");
-        let mut current = self.current.clone();
+    fn identifier_type(&self) -> TokenType {
+        match self.lexeme() {
+            "and" => TokenType::And,
+            "class" => TokenType::Class,
+            "else" => TokenType::Else,
+            "false" => TokenType::False,
+            "for" => TokenType::For,
+            "fun" => TokenType::Fun,
+            "if" => TokenType::If,
+            "nil" => TokenType::Nil,
+            "or" => TokenType::Or,
+            "print" => TokenType::Print,
+            "return" => TokenType::Return,
+            "super" => TokenType::Super,
+            "this" => TokenType::This,
+            "true" => TokenType::True,
+            "var" => TokenType::Var,
+            "while" => TokenType::While,
+            _ => TokenType::Identifier,
+        }
+    }
+}
+
+fn is_alpha(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
 
-        if let Some(c_ref) = current.peek() {
-            let mut binding = *c_ref;
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
 
-            while self.is_alpha(binding) || binding.is_digit(10) {
-                match self.advance() {
-                    Some(next) => {
-                        // println!("char: {}", next);
-                        binding = next
-                    }
-                    None => break,
+    use super::*;
+
+    /// One line per token, as "line:column Type lexeme".
+    fn describe_tokens(source: &str) -> String {
+        let mut scanner = Scanner::new(source);
+        let mut description = String::new();
+
+        loop {
+            let line = match scanner.scan_token() {
+                ScanResult::Normal(token) => format!(
+                    "{}:{} {:?} {}",
+                    token.line,
+                    token.column,
+                    token.r#type,
+                    token.lexeme.replace('\n', "\\n")
+                ),
+                ScanResult::Error(error) => format!(
+                    "{}:{} Error {} {}",
+                    error.line,
+                    error.column,
+                    error.message,
+                    error.lexeme.replace('\n', "\\n")
+                ),
+                ScanResult::EOF(token) => {
+                    description.push_str(&format!("{} EOF\n", token.line));
+                    return description;
                 }
-            }
+            };
 
-            // println!("char now: {}", self.current_index_in_lexeme);
+            description.push_str(&line);
+            description.push('\n');
         }
-        let ident_type = self.identifier_type();
-        ScanResult::Normal(self.make_token(ident_type))
     }
 
-    fn identifier_type(&mut self) -> TokenType {
-        // Figure out if we really need to clone here
-        let mut ident_or_keyword = self.start.clone();
-
-        match ident_or_keyword.next() {
-            Some('a') => self.check_keyword("nd", &mut ident_or_keyword, TokenType::And),
-            Some('c') => self.check_keyword("lass", &mut ident_or_keyword, TokenType::Class),
-            Some('e') => self.check_keyword("lse", &mut ident_or_keyword, TokenType::Else),
-            Some('i') => self.check_keyword("f", &mut ident_or_keyword, TokenType::If),
-            Some('n') => self.check_keyword("nil", &mut ident_or_keyword, TokenType::Nil),
-            Some('o') => self.check_keyword("r", &mut ident_or_keyword, TokenType::Or),
-            Some('p') => self.check_keyword("rint", &mut ident_or_keyword, TokenType::Print),
-            Some('r') => self.check_keyword("eturn", &mut ident_or_keyword, TokenType::Return),
-            Some('s') => self.check_keyword("uper", &mut ident_or_keyword, TokenType::Super),
-            Some('v') => self.check_keyword("ar", &mut ident_or_keyword, TokenType::Var),
-            Some('w') => self.check_keyword("hile", &mut ident_or_keyword, TokenType::While),
-            Some('f') => {
-                if let Some(c) = ident_or_keyword.next() {
-                    match c {
-                        'a' => self.check_keyword("lse", &mut ident_or_keyword, TokenType::False),
-                        'o' => self.check_keyword("r", &mut ident_or_keyword, TokenType::For),
-                        'u' => self.check_keyword("n", &mut ident_or_keyword, TokenType::Fun),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
+    /// Scans every `tests/scanner/*.lox` fixture and compares the tokens to
+    /// the `.tokens` file next to it. Run with `UPDATE_FIXTURES=1` to rewrite
+    /// the expectations instead.
+    #[test]
+    fn scanner_fixtures() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scanner");
+        let update = env::var_os("UPDATE_FIXTURES").is_some();
+        let mut checked = 0;
+
+        for entry in fs::read_dir(&fixtures).expect("Failed to read the fixtures") {
+            let path = entry.expect("Failed to read a fixture").path();
+
+            if path.extension().is_none_or(|extension| extension != "lox") {
+                continue;
             }
-            Some('t') => {
-                if let Some(c) = ident_or_keyword.next() {
-                    match c {
-                        'h' => self.check_keyword("is", &mut ident_or_keyword, TokenType::This),
-                        'r' => self.check_keyword("rue", &mut ident_or_keyword, TokenType::True),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
+
+            let source = fs::read_to_string(&path).expect("Failed to read a fixture");
+            let actual = describe_tokens(&source);
+            let expected_path = path.with_extension("tokens");
+
+            if update {
+                fs::write(&expected_path, &actual).expect("Failed to write a fixture");
+            } else {
+                let expected =
+                    fs::read_to_string(&expected_path).expect("Failed to read a fixture");
+                assert_eq!(actual, expected, "in {}", path.display());
             }
-            _ => TokenType::Identifier,
+
+            checked += 1;
         }
+
+        assert!(checked > 0, "No fixtures in {}", fixtures.display());
     }
+}
 
-    fn check_keyword(
-        &mut self,
-        rest: &str,
-        lexeme: &mut Peekable<Chars<'a>>,
-        r#type: TokenType,
-    ) -> TokenType {
-        let len = rest.len();
-
-        if lexeme.clone().take(len).eq(rest.chars()) {
-            r#type
-        } else {
-            TokenType::Identifier
+#[cfg(test)]
+mod benches {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    use super::*;
+
+    const SNIPPET: &str = "class Point < Shape {
+  init(x, y) { this.x = x; this.y = y; } // a comment
+  length() { return sqrt(this.x * this.x + this.y * this.y); }
+}
+var total = 0;
+for (var i = 0; i < 1000; i = i + 1) { total = total + Point(i, 2.5).length(); }
+print \"total: \" + str(total);
+";
+
+    /// Counts allocations per thread, so tests running in parallel don't
+    /// show up in each other's counts.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
         }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Scans all of `source`, returning the number of tokens and how many
+    /// allocations that took.
+    fn scan_all(source: &str) -> (usize, usize) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let mut scanner = Scanner::new(source);
+        let mut tokens = 0usize;
+
+        loop {
+            match black_box(scanner.scan_token()) {
+                ScanResult::Normal(_) => tokens += 1,
+                ScanResult::EOF(_) => break,
+                ScanResult::Error(error) => panic!("{}", error.message),
+            }
+        }
+
+        (tokens, ALLOCATIONS.with(Cell::get) - before)
+    }
+
+    #[test]
+    fn scanning_does_not_allocate() {
+        let source = SNIPPET.repeat(10);
+        let (tokens, allocations) = scan_all(&source);
+
+        assert!(tokens > 0);
+        assert_eq!(allocations, 0);
+    }
+
+    /// Scans about 10 MB of Lox. Tokens only borrow from the source, so
+    /// building the source is the only allocation.
+    ///
+    /// cargo test --release -- --ignored --nocapture bench_scanner
+    #[test]
+    #[ignore]
+    fn bench_scanner() {
+        const SIZE: usize = 10 * 1024 * 1024;
+
+        let source = SNIPPET.repeat(SIZE / SNIPPET.len() + 1);
+
+        let start = Instant::now();
+        let (tokens, allocations) = scan_all(&source);
+        let elapsed = start.elapsed();
+
+        assert_eq!(allocations, 0);
+        println!(
+            "Scanned {} bytes into {} tokens in {:?} ({:.0} MB/s)",
+            source.len(),
+            tokens,
+            elapsed,
+            source.len() as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+        );
     }
 }
@@ -0,0 +1,285 @@
+use std::ptr;
+
+use crate::object::ObjString;
+use crate::value::Value;
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+/// An empty bucket has a null key and a `nil` value. A tombstone left behind
+/// by `delete` has a null key and a `true` value so probing continues past it.
+#[derive(Copy, Clone)]
+pub(crate) struct Entry {
+    pub(crate) key: *mut ObjString,
+    pub(crate) value: Value,
+}
+
+impl Entry {
+    fn empty() -> Self {
+        Self {
+            key: ptr::null_mut(),
+            value: Value::Nil,
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.key.is_null() && !matches!(self.value, Value::Nil)
+    }
+}
+
+/// Open-addressing hash table with linear probing, keyed by interned strings.
+/// Since keys are interned, comparing them is a pointer comparison.
+#[derive(Default)]
+pub(crate) struct Table {
+    /// Number of live entries plus tombstones
+    count: usize,
+    pub(crate) entries: Vec<Entry>,
+}
+
+impl Table {
+    pub(crate) fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn get(&self, key: *mut ObjString) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[Table::find_entry(&self.entries, key)];
+
+        if entry.key.is_null() {
+            None
+        } else {
+            Some(entry.value)
+        }
+    }
+
+    /// Returns true if `key` was not already in the table.
+    pub(crate) fn set(&mut self, key: *mut ObjString, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.capacity() as f64 * TABLE_MAX_LOAD {
+            let capacity = grow_capacity(self.capacity());
+            self.adjust_capacity(capacity);
+        }
+
+        let index = Table::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_null();
+
+        // Reusing a tombstone doesn't change the count, it was already included
+        if is_new_key && matches!(entry.value, Value::Nil) {
+            self.count += 1;
+        }
+
+        entry.key = key;
+        entry.value = value;
+        is_new_key
+    }
+
+    pub(crate) fn delete(&mut self, key: *mut ObjString) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        let index = Table::find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+
+        if entry.key.is_null() {
+            return false;
+        }
+
+        entry.key = ptr::null_mut();
+        entry.value = Value::Bool(true);
+        true
+    }
+
+    pub(crate) fn add_all(&mut self, from: &Table) {
+        for entry in from.entries.iter() {
+            if !entry.key.is_null() {
+                self.set(entry.key, entry.value);
+            }
+        }
+    }
+
+    /// Removes every entry whose key wasn't marked by the collector. Used on
+    /// the interned strings table, which must not keep strings alive itself.
+    pub(crate) fn remove_white(&mut self) {
+        for i in 0..self.entries.len() {
+            let key = self.entries[i].key;
+
+            if !key.is_null() && unsafe { !(*key).obj.is_marked } {
+                self.delete(key);
+            }
+        }
+    }
+
+    /// Looks a string up by its contents rather than its identity. This is
+    /// what interning uses to decide whether a string already exists.
+    pub(crate) fn find_string(&self, chars: &str, hash: u32) -> Option<*mut ObjString> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mut index = hash as usize & (self.capacity() - 1);
+
+        loop {
+            let entry = &self.entries[index];
+
+            if entry.key.is_null() {
+                // Stop if we find an empty non-tombstone entry
+                if !entry.is_tombstone() {
+                    return None;
+                }
+            } else {
+                let key = unsafe { &*entry.key };
+
+                if key.hash == hash && key.chars == chars {
+                    return Some(entry.key);
+                }
+            }
+
+            index = (index + 1) & (self.capacity() - 1);
+        }
+    }
+
+    fn find_entry(entries: &[Entry], key: *mut ObjString) -> usize {
+        let capacity = entries.len();
+        let mut index = unsafe { (*key).hash } as usize & (capacity - 1);
+        let mut tombstone = None;
+
+        loop {
+            let entry = &entries[index];
+
+            if entry.key.is_null() {
+                if entry.is_tombstone() {
+                    tombstone.get_or_insert(index);
+                } else {
+                    // Prefer handing out an earlier tombstone so it gets reused
+                    return tombstone.unwrap_or(index);
+                }
+            } else if entry.key == key {
+                return index;
+            }
+
+            index = (index + 1) & (capacity - 1);
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::empty(); capacity];
+
+        // Tombstones aren't copied over, so the count is rebuilt from scratch
+        self.count = 0;
+
+        for entry in self.entries.iter() {
+            if entry.key.is_null() {
+                continue;
+            }
+
+            let index = Table::find_entry(&entries, entry.key);
+            entries[index] = *entry;
+            self.count += 1;
+        }
+
+        self.entries = entries;
+    }
+}
+
+fn grow_capacity(capacity: usize) -> usize {
+    if capacity < 8 {
+        8
+    } else {
+        capacity * 2
+    }
+}
+
+#[cfg(test)]
+mod benches {
+    use std::collections::HashMap;
+    use std::hint::black_box;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::vm::VM;
+
+    fn keys(vm: &mut VM, count: usize) -> Vec<*mut ObjString> {
+        (0..count)
+            .map(|i| vm.copy_string(&format!("key{}", i)))
+            .collect()
+    }
+
+    fn time(mut f: impl FnMut()) -> Duration {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }
+
+    /// Mimics global variable traffic: every key is defined once, then read
+    /// and reassigned repeatedly, with some churn from deletes.
+    ///
+    /// cargo test --release -- --ignored --nocapture bench_table
+    #[test]
+    #[ignore]
+    fn bench_table_vs_hashmap() {
+        const KEYS: usize = 1_000;
+        const ROUNDS: usize = 10_000;
+
+        let mut vm = VM::new();
+        let keys = keys(&mut vm, KEYS);
+
+        let table_time = time(|| {
+            let mut table = Table::new();
+
+            for key in keys.iter() {
+                table.set(*key, Value::Nil);
+            }
+
+            for round in 0..ROUNDS {
+                for key in keys.iter() {
+                    black_box(table.get(*key));
+                    table.set(*key, Value::Number(round as f64));
+                }
+
+                let key = keys[round % KEYS];
+                table.delete(key);
+                table.set(key, Value::Nil);
+            }
+        });
+
+        let hashmap_time = time(|| {
+            let mut map = HashMap::new();
+
+            for key in keys.iter() {
+                map.insert(*key, Value::Nil);
+            }
+
+            for round in 0..ROUNDS {
+                for key in keys.iter() {
+                    black_box(map.get(key));
+                    map.insert(*key, Value::Number(round as f64));
+                }
+
+                let key = keys[round % KEYS];
+                map.remove(&key);
+                map.insert(key, Value::Nil);
+            }
+        });
+
+        let interning_time = time(|| {
+            for key in keys.iter() {
+                let (chars, hash) = unsafe { ((**key).chars.as_str(), (**key).hash) };
+
+                for _ in 0..ROUNDS / 10 {
+                    black_box(vm.strings.find_string(chars, hash));
+                }
+            }
+        });
+
+        println!("Table:   {:?}", table_time);
+        println!("HashMap: {:?}", hashmap_time);
+        println!("Table::find_string: {:?}", interning_time);
+    }
+}
@@ -1,4 +1,107 @@
-pub(crate) type Value = f64;
+use std::fmt;
+
+use crate::object::{
+    self, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative,
+    ObjString, ObjType,
+};
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(*mut Obj),
+}
+
+impl Value {
+    /// Lox treats `nil` and `false` as falsey and every other value as truthy.
+    pub(crate) fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub(crate) fn is_obj_type(&self, r#type: ObjType) -> bool {
+        match self {
+            Value::Obj(object) => unsafe { (**object).r#type == r#type },
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_string(&self) -> bool {
+        self.is_obj_type(ObjType::String)
+    }
+
+    /// Callers must check `is_string` first.
+    pub(crate) fn as_string(&self) -> &ObjString {
+        match self {
+            Value::Obj(object) => unsafe { &*(*object as *const ObjString) },
+            _ => unreachable!("Value is not a string"),
+        }
+    }
+}
+
+impl From<*mut ObjBoundMethod> for Value {
+    fn from(bound: *mut ObjBoundMethod) -> Self {
+        Value::Obj(bound as *mut Obj)
+    }
+}
+
+impl From<*mut ObjClass> for Value {
+    fn from(class: *mut ObjClass) -> Self {
+        Value::Obj(class as *mut Obj)
+    }
+}
+
+impl From<*mut ObjClosure> for Value {
+    fn from(closure: *mut ObjClosure) -> Self {
+        Value::Obj(closure as *mut Obj)
+    }
+}
+
+impl From<*mut ObjFunction> for Value {
+    fn from(function: *mut ObjFunction) -> Self {
+        Value::Obj(function as *mut Obj)
+    }
+}
+
+impl From<*mut ObjInstance> for Value {
+    fn from(instance: *mut ObjInstance) -> Self {
+        Value::Obj(instance as *mut Obj)
+    }
+}
+
+impl From<*mut ObjNative> for Value {
+    fn from(native: *mut ObjNative) -> Self {
+        Value::Obj(native as *mut Obj)
+    }
+}
+
+impl From<*mut ObjString> for Value {
+    fn from(string: *mut ObjString) -> Self {
+        Value::Obj(string as *mut Obj)
+    }
+}
+
+pub(crate) fn values_equal(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        // Strings are interned, so identity is equality
+        (Value::Obj(a), Value::Obj(b)) => a == b,
+        _ => false,
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Obj(object) => object::fmt_object(object, f),
+        }
+    }
+}
 
 pub(crate) fn print_value(value: Value) {
     print!("{}", value);
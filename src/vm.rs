@@ -1,59 +1,231 @@
-use std::iter::Peekable;
+use std::fmt::Write;
+use std::ptr;
+use std::rc::Rc;
 
 use crate::chunk::{Chunk, Opcode};
 use crate::compiler;
 use crate::debug::Disassembler;
+use crate::diagnostics::{Diagnostic, Emitter, Location, Span};
+use crate::object::{
+    NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative,
+    ObjString, ObjType, ObjUpvalue,
+};
+use crate::table::Table;
 use crate::value;
 use crate::value::Value;
 
 macro_rules! binary_op {
-    ($vm:ident, $op:tt) => {{
-	let right = $vm.pop();
-	let left = $vm.pop();
+    ($vm:ident, $value_type:path, $op:tt) => {{
+	let right = $vm.pop()?;
+	let left = $vm.pop()?;
 
-	$vm.push(left $op right);
+	match (left, right) {
+	    (Value::Number(a), Value::Number(b)) => $vm.push($value_type(a $op b)),
+	    _ => return Err(RuntimeError::new("Operands must be numbers.")),
+	}
     }};
 }
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
 
+/// A single ongoing function call. `slot_base` is the index of the callee in
+/// the VM stack, which is also where the function's local slot zero lives.
+pub(crate) struct CallFrame {
+    pub(crate) closure: *mut ObjClosure,
+    pub(crate) ip: usize,
+    pub(crate) slot_base: usize,
+}
+
+pub(crate) struct RuntimeError {
+    pub(crate) message: String,
+}
+
+impl RuntimeError {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum InterpretResult {
     InterpretOk,
     InterpretCompileError,
     InterpretRuntimeError,
 }
 
-pub(crate) struct VM<'a> {
-    pub(crate) chunk: &'a Chunk,
+pub(crate) struct VM {
     pub(crate) debug_trace_execution: bool,
-    ip: Peekable<std::slice::Iter<'a, u8>>,
-    stack: Vec<Value>,
+    pub(crate) debug_print_code: bool,
+    pub(crate) frames: Vec<CallFrame>,
+    pub(crate) stack: Vec<Value>,
+    pub(crate) strings: Table,
+    pub(crate) globals: Table,
+    pub(crate) objects: *mut Obj,
+    pub(crate) open_upvalues: *mut ObjUpvalue,
+    /// Functions the compiler is still emitting code into
+    pub(crate) compiler_roots: Vec<*mut ObjFunction>,
+    pub(crate) gray_stack: Vec<*mut Obj>,
+    pub(crate) bytes_allocated: usize,
+    pub(crate) next_gc: usize,
+    /// Collect before every allocation, to shake out missing roots
+    pub(crate) stress_gc: bool,
+    pub(crate) log_gc: bool,
+    /// Interned "init", looked up on every class call
+    pub(crate) init_string: *mut ObjString,
+    /// Reports compile and runtime errors
+    pub(crate) emitter: Emitter,
 }
-impl<'a> VM<'a> {
-    pub(crate) fn new(chunk: &'a Chunk) -> Self {
-        Self {
-            chunk,
-            ip: chunk.code.iter().peekable(),
+
+impl VM {
+    pub(crate) fn new() -> Self {
+        let mut vm = Self {
             debug_trace_execution: false,
+            debug_print_code: false,
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: Vec::with_capacity(STACK_MAX),
+            strings: Table::new(),
+            globals: Table::new(),
+            objects: ptr::null_mut(),
+            open_upvalues: ptr::null_mut(),
+            compiler_roots: Vec::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: 1024 * 1024,
+            stress_gc: false,
+            log_gc: false,
+            init_string: ptr::null_mut(),
+            emitter: Emitter::new(),
+        };
+
+        vm.init_string = vm.copy_string("init");
+        vm.define_natives();
+        vm
+    }
+
+    /// Exposes `function` to Lox code as a global called `name`.
+    pub(crate) fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // Both objects are kept on the stack so a collection can't free them
+        let name = self.copy_string(name);
+        self.push(name.into());
+        let native = self.new_native(name, arity, function);
+        self.push(native.into());
+
+        self.globals.set(name, native.into());
+        self.stack.truncate(self.stack.len() - 2);
+    }
+
+    pub(crate) fn new_with_debug(debug_trace_execution: bool, debug_print_code: bool) -> Self {
+        let mut vm = VM::new();
+        vm.debug_trace_execution = debug_trace_execution;
+        vm.debug_print_code = debug_print_code;
+        vm
+    }
+
+    pub(crate) fn interpret(&mut self, source: &str) -> InterpretResult {
+        let function = match compiler::compile(source, self) {
+            Ok(function) => function,
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    self.emitter.emit(diagnostic);
+                }
+
+                return InterpretResult::InterpretCompileError;
+            }
+        };
+
+        // Keep the function reachable while the closure is allocated
+        self.push(function.into());
+        let closure = self.new_closure(function);
+        self.stack.pop();
+        self.push(closure.into());
+
+        if let Err(error) = self.call(closure, 0) {
+            return self.runtime_error(&error);
         }
+
+        self.run()
     }
 
-    pub(crate) fn new_with_debug(chunk: &'a Chunk, debug_trace_execution: bool) -> Self {
-        Self {
-            chunk,
-            ip: chunk.code.iter().peekable(),
-            debug_trace_execution,
-            stack: Vec::with_capacity(STACK_MAX),
+    fn run(&mut self) -> InterpretResult {
+        match self.execute() {
+            Ok(()) => InterpretResult::InterpretOk,
+            Err(error) => self.runtime_error(&error),
         }
     }
 
-    pub(crate) fn interpret(&mut self, source: &str) -> InterpretResult {
-	compiler::compile(source);
-	return InterpretResult::InterpretOk
+    fn runtime_error(&mut self, error: &RuntimeError) -> InterpretResult {
+        let diagnostic = self.runtime_diagnostic(error);
+        self.emitter.emit(&diagnostic);
+
+        self.reset_stack();
+        InterpretResult::InterpretRuntimeError
     }
 
-    pub(crate) fn run(&mut self) -> InterpretResult {
+    /// Points `error` at the instruction that raised it, with a note for
+    /// every call on the stack, innermost first. Each frame is shown in the
+    /// source its function was compiled from, which in the REPL may be an
+    /// earlier line.
+    fn runtime_diagnostic(&self, error: &RuntimeError) -> Diagnostic {
+        let frames: Vec<(*mut ObjFunction, Span)> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = unsafe { (*frame.closure).function };
+
+                // The failing instruction has already been read, so ip is one past it
+                let positions = unsafe { &(*function).chunk.positions };
+                (
+                    function,
+                    Chunk::get_span(frame.ip.saturating_sub(1), positions),
+                )
+            })
+            .collect();
+
+        let mut diagnostic: Option<Diagnostic> = None;
+
+        // Recursion repeats the same call, so a run of identical frames is
+        // shown once with a count
+        for run in frames.chunk_by(|a, b| a == b) {
+            let (function, span) = run[0];
+            let function = unsafe { &*function };
+            let location = Location::new(Rc::clone(&function.chunk.source), span);
+
+            let mut frame_name = if function.name.is_null() {
+                String::from("in script")
+            } else {
+                format!("in {}()", unsafe { &(*function.name).chars })
+            };
+            if run.len() > 1 {
+                write!(frame_name, " ({} more times)", run.len() - 1).unwrap();
+            }
+
+            // Only the calls further out need their own snippet
+            diagnostic = Some(match diagnostic {
+                None => {
+                    Diagnostic::new(error.message.clone(), location).with_note(frame_name, None)
+                }
+                Some(diagnostic) => diagnostic.with_note(frame_name, Some(location)),
+            });
+        }
+
+        diagnostic.unwrap_or_else(|| {
+            let location = Location::new(Rc::from(""), Span::new(0, 0, 1, 1));
+            Diagnostic::new(error.message.clone(), location)
+        })
+    }
+
+    fn reset_stack(&mut self) {
+        // Closures that escaped before an error may outlive the stack
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+    }
+
+    fn execute(&mut self) -> Result<(), RuntimeError> {
         loop {
             if self.debug_trace_execution {
                 print!("          ");
@@ -65,55 +237,1063 @@ impl<'a> VM<'a> {
                 }
                 println!();
 
-                let mut dis = Disassembler::new(self.chunk, "VM_DISASSEMBLER");
-                dis.disassemble_instruction((**self.ip.peek().unwrap()).into());
+                let ip = self.frame().ip;
+                Disassembler::new_at(self.chunk(), "VM_DISASSEMBLER", ip).disassemble_instruction();
             }
 
-            match Opcode::from(*self.ip.next().unwrap()) {
+            let opcode = Opcode::try_from(self.read_byte())
+                .map_err(|byte| RuntimeError::new(format!("Unknown opcode {}.", byte)))?;
+            let depth = self.stack.len();
+
+            match opcode {
+                Opcode::OpCall => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count)?, arg_count)?;
+                }
+
                 Opcode::OpReturn => {
-                    value::print_value(self.pop());
+                    let result = self.pop()?;
+                    let frame = self.frames.pop().expect("Returning requires a call frame");
+                    self.close_upvalues(frame.slot_base);
+
+                    if self.frames.is_empty() {
+                        // Pop the top-level script and exit the interpreter
+                        self.pop()?;
+                        return Ok(());
+                    }
+
+                    self.stack.truncate(frame.slot_base);
+                    self.push(result);
+                }
+
+                Opcode::OpPrint => {
+                    value::print_value(self.pop()?);
                     println!();
-                    return InterpretResult::InterpretOk;
                 }
 
-                Opcode::OpConstant => {
-                    let constant = self.read_constant();
+                Opcode::OpPop => {
+                    self.pop()?;
+                }
+
+                Opcode::OpPopN => {
+                    let count = self.read_byte() as usize;
+
+                    if count > self.stack.len() {
+                        return Err(RuntimeError::new("Stack underflow."));
+                    }
+                    self.stack.truncate(self.stack.len() - count);
+                }
+
+                Opcode::OpJump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+
+                Opcode::OpJumpIfFalse => {
+                    let offset = self.read_short();
+
+                    if self.peek(0)?.is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+
+                Opcode::OpLoop => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+
+                Opcode::OpGetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte() as usize;
+                    self.push(self.stack[slot]);
+                }
+
+                Opcode::OpSetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0)?;
+                }
+
+                Opcode::OpGetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.current_upvalue(slot);
+
+                    let value = unsafe {
+                        match (*upvalue).closed {
+                            Some(value) => value,
+                            None => self.stack[(*upvalue).location],
+                        }
+                    };
+                    self.push(value);
+                }
+
+                Opcode::OpSetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.current_upvalue(slot);
+                    let value = self.peek(0)?;
+
+                    unsafe {
+                        match (*upvalue).closed {
+                            Some(_) => (*upvalue).closed = Some(value),
+                            None => self.stack[(*upvalue).location] = value,
+                        }
+                    }
+                }
+
+                Opcode::OpClosure | Opcode::OpClosureLong => {
+                    let function = match self.read_constant(opcode.constant_width()) {
+                        Value::Obj(function) => function as *mut ObjFunction,
+                        _ => unreachable!("OP_CLOSURE always refers to a function"),
+                    };
+
+                    let closure = self.new_closure(function);
+                    self.push(closure.into());
+
+                    for _ in 0..unsafe { (*function).upvalue_count } {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slot_base + index)
+                        } else {
+                            self.current_upvalue(index)
+                        };
+
+                        unsafe {
+                            (*closure).upvalues.push(upvalue);
+                        }
+                    }
+                }
+
+                Opcode::OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop()?;
+                }
+
+                Opcode::OpDefineGlobal | Opcode::OpDefineGlobalLong => {
+                    let name = self.read_string(opcode.constant_width());
+                    let value = self.peek(0)?;
+                    self.globals.set(name, value);
+                    self.pop()?;
+                }
+
+                Opcode::OpGetGlobal | Opcode::OpGetGlobalLong => {
+                    let name = self.read_string(opcode.constant_width());
+
+                    match self.globals.get(name) {
+                        Some(value) => self.push(value),
+                        None => return Err(VM::undefined_variable(name)),
+                    }
+                }
+
+                Opcode::OpSetGlobal | Opcode::OpSetGlobalLong => {
+                    let name = self.read_string(opcode.constant_width());
+                    let value = self.peek(0)?;
+
+                    // Assignment never implicitly declares a global
+                    if self.globals.set(name, value) {
+                        self.globals.delete(name);
+                        return Err(VM::undefined_variable(name));
+                    }
+                }
+
+                Opcode::OpClass | Opcode::OpClassLong => {
+                    let name = self.read_string(opcode.constant_width());
+                    let class = self.new_class(name);
+                    self.push(class.into());
+                }
+
+                Opcode::OpGetProperty | Opcode::OpGetPropertyLong => {
+                    let instance = match self.peek(0)? {
+                        Value::Obj(object) if self.peek(0)?.is_obj_type(ObjType::Instance) => {
+                            object as *mut ObjInstance
+                        }
+                        _ => return Err(RuntimeError::new("Only instances have properties.")),
+                    };
+                    let name = self.read_string(opcode.constant_width());
+
+                    // Fields shadow methods
+                    match unsafe { (*instance).fields.get(name) } {
+                        Some(value) => {
+                            self.pop()?;
+                            self.push(value);
+                        }
+                        None => self.bind_method(unsafe { (*instance).class }, name)?,
+                    }
+                }
+
+                Opcode::OpSetProperty | Opcode::OpSetPropertyLong => {
+                    let instance = match self.peek(1)? {
+                        Value::Obj(object) if self.peek(1)?.is_obj_type(ObjType::Instance) => {
+                            object as *mut ObjInstance
+                        }
+                        _ => return Err(RuntimeError::new("Only instances have fields.")),
+                    };
+                    let name = self.read_string(opcode.constant_width());
+
+                    // Leave the assigned value as the result of the expression
+                    let value = self.pop()?;
+                    self.grow_table(unsafe { &mut (*instance).fields }, |fields| {
+                        fields.set(name, value)
+                    });
+                    self.pop()?;
+                    self.push(value);
+                }
+
+                Opcode::OpMethod | Opcode::OpMethodLong => {
+                    let name = self.read_string(opcode.constant_width());
+                    self.define_method(name)?;
+                }
+
+                Opcode::OpInvoke | Opcode::OpInvokeLong => {
+                    let name = self.read_string(opcode.constant_width());
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(name, arg_count)?;
+                }
+
+                Opcode::OpInherit => {
+                    let superclass = match self.peek(1)? {
+                        Value::Obj(object) if self.peek(1)?.is_obj_type(ObjType::Class) => {
+                            object as *mut ObjClass
+                        }
+                        _ => return Err(RuntimeError::new("Superclass must be a class.")),
+                    };
+                    let subclass = match self.peek(0)? {
+                        Value::Obj(object) => object as *mut ObjClass,
+                        _ => unreachable!("OP_INHERIT always follows the subclass"),
+                    };
+
+                    // Copy-down inheritance: the subclass's own methods are
+                    // defined afterwards and override these
+                    self.grow_table(unsafe { &mut (*subclass).methods }, |methods| {
+                        methods.add_all(unsafe { &(*superclass).methods })
+                    });
+                    self.pop()?;
+                }
+
+                Opcode::OpGetSuper | Opcode::OpGetSuperLong => {
+                    let name = self.read_string(opcode.constant_width());
+                    let superclass = self.pop_class()?;
+                    self.bind_method(superclass, name)?;
+                }
+
+                Opcode::OpSuperInvoke | Opcode::OpSuperInvokeLong => {
+                    let name = self.read_string(opcode.constant_width());
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = self.pop_class()?;
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+
+                Opcode::OpConstant | Opcode::OpConstantLong => {
+                    let constant = self.read_constant(opcode.constant_width());
                     self.push(constant);
                 }
 
-                Opcode::OpNegate => {
-                    let operand = self.pop();
-                    self.push(-operand);
+                Opcode::OpNil => self.push(Value::Nil),
+                Opcode::OpTrue => self.push(Value::Bool(true)),
+                Opcode::OpFalse => self.push(Value::Bool(false)),
+
+                Opcode::OpEqual => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(Value::Bool(value::values_equal(a, b)));
+                }
+
+                Opcode::OpNot => {
+                    let operand = self.pop()?;
+                    self.push(Value::Bool(operand.is_falsey()));
                 }
 
-                Opcode::OpAdd => self.do_binary_op('+'),
-                Opcode::OpSubtract => self.do_binary_op('-'),
-                Opcode::OpMultiply => self.do_binary_op('*'),
-                Opcode::OpDivide => self.do_binary_op('/'),
-                _ => panic!(),
+                Opcode::OpNegate => match self.pop()? {
+                    Value::Number(n) => self.push(Value::Number(-n)),
+                    _ => return Err(RuntimeError::new("Operand must be a number.")),
+                },
+
+                Opcode::OpGreater => self.do_binary_op('>')?,
+                Opcode::OpLess => self.do_binary_op('<')?,
+                Opcode::OpAdd => {
+                    let b = self.peek(0)?;
+                    let a = self.peek(1)?;
+
+                    match (a, b) {
+                        (Value::Number(_), Value::Number(_)) => self.do_binary_op('+')?,
+                        _ if a.is_string() && b.is_string() => self.concatenate()?,
+                        _ => {
+                            return Err(RuntimeError::new(
+                                "Operands must be two numbers or two strings.",
+                            ))
+                        }
+                    }
+                }
+                Opcode::OpSubtract => self.do_binary_op('-')?,
+                Opcode::OpMultiply => self.do_binary_op('*')?,
+                Opcode::OpDivide => self.do_binary_op('/')?,
+            }
+
+            // Catches the opcode table and the VM disagreeing about an
+            // instruction
+            if let Some(effect) = opcode.stack_effect() {
+                debug_assert_eq!(
+                    self.stack.len() as isize - depth as isize,
+                    effect as isize,
+                    "{} has the wrong stack effect",
+                    opcode.name()
+                );
             }
         }
     }
 
-    pub(crate) fn do_binary_op(&mut self, op: char) {
+    pub(crate) fn do_binary_op(&mut self, op: char) -> Result<(), RuntimeError> {
         match op {
-            '+' => binary_op!(self, +),
-            '-' => binary_op!(self, -),
-            '*' => binary_op!(self, *),
-            '/' => binary_op!(self, /),
-            _ => panic!("Invalid operator"),
+            '>' => binary_op!(self, Value::Bool, >),
+            '<' => binary_op!(self, Value::Bool, <),
+            '+' => binary_op!(self, Value::Number, +),
+            '-' => binary_op!(self, Value::Number, -),
+            '*' => binary_op!(self, Value::Number, *),
+            '/' => binary_op!(self, Value::Number, /),
+            _ => unreachable!("Invalid operator"),
+        }
+
+        Ok(())
+    }
+
+    fn concatenate(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+
+        let mut chars =
+            String::with_capacity(a.as_string().chars.len() + b.as_string().chars.len());
+        chars.push_str(&a.as_string().chars);
+        chars.push_str(&b.as_string().chars);
+
+        let result = self.take_string(chars);
+        self.push(result.into());
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        match callee {
+            Value::Obj(object) if callee.is_obj_type(ObjType::Native) => {
+                let native = object as *mut ObjNative;
+                let (arity, function) = unsafe { ((*native).arity, (*native).function) };
+
+                if arg_count != arity {
+                    return Err(RuntimeError::new(format!(
+                        "Expected {} arguments but got {}.",
+                        arity, arg_count
+                    )));
+                }
+
+                // The arguments are copied out so the native can allocate,
+                // they stay on the stack meanwhile to keep them reachable
+                let args_start = self.stack.len() - arg_count;
+                let args = self.stack[args_start..].to_vec();
+                let result = function(self, &args)?;
+
+                self.stack.truncate(args_start - 1);
+                self.push(result);
+                Ok(())
+            }
+            Value::Obj(object) if callee.is_obj_type(ObjType::BoundMethod) => {
+                let bound = object as *mut ObjBoundMethod;
+
+                // The receiver takes the method's place in slot zero as `this`
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = unsafe { (*bound).receiver };
+                self.call(unsafe { (*bound).method }, arg_count)
+            }
+            Value::Obj(object) if callee.is_obj_type(ObjType::Class) => {
+                let class = object as *mut ObjClass;
+                let instance = self.new_instance(class);
+
+                // The instance takes the class's place below the arguments
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = instance.into();
+
+                match unsafe { (*class).methods.get(self.init_string) } {
+                    Some(Value::Obj(initializer)) => {
+                        self.call(initializer as *mut ObjClosure, arg_count)
+                    }
+                    _ if arg_count != 0 => Err(RuntimeError::new(format!(
+                        "Expected 0 arguments but got {}.",
+                        arg_count
+                    ))),
+                    _ => Ok(()),
+                }
+            }
+            Value::Obj(object) if callee.is_obj_type(ObjType::Closure) => {
+                self.call(object as *mut ObjClosure, arg_count)
+            }
+            _ => Err(RuntimeError::new("Can only call functions and classes.")),
+        }
+    }
+
+    /// Calls the method `name` on the receiver below the arguments without
+    /// allocating a bound method for it first.
+    fn invoke(&mut self, name: *mut ObjString, arg_count: usize) -> Result<(), RuntimeError> {
+        let receiver = self.peek(arg_count)?;
+
+        let instance = match receiver {
+            Value::Obj(object) if receiver.is_obj_type(ObjType::Instance) => {
+                object as *mut ObjInstance
+            }
+            _ => return Err(RuntimeError::new("Only instances have methods.")),
+        };
+
+        // A field holding a function is called like any other value
+        if let Some(value) = unsafe { (*instance).fields.get(name) } {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(unsafe { (*instance).class }, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: *mut ObjClass,
+        name: *mut ObjString,
+        arg_count: usize,
+    ) -> Result<(), RuntimeError> {
+        match unsafe { (*class).methods.get(name) } {
+            Some(Value::Obj(method)) => self.call(method as *mut ObjClosure, arg_count),
+            _ => Err(VM::undefined_property(name)),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`,
+    /// bound to it.
+    fn bind_method(
+        &mut self,
+        class: *mut ObjClass,
+        name: *mut ObjString,
+    ) -> Result<(), RuntimeError> {
+        let method = match unsafe { (*class).methods.get(name) } {
+            Some(Value::Obj(method)) => method as *mut ObjClosure,
+            _ => return Err(VM::undefined_property(name)),
+        };
+
+        let bound = self.new_bound_method(self.peek(0)?, method);
+        self.pop()?;
+        self.push(bound.into());
+        Ok(())
+    }
+
+    fn define_method(&mut self, name: *mut ObjString) -> Result<(), RuntimeError> {
+        let method = self.peek(0)?;
+
+        match self.peek(1)? {
+            Value::Obj(class) => {
+                self.grow_table(
+                    unsafe { &mut (*(class as *mut ObjClass)).methods },
+                    |methods| methods.set(name, method),
+                );
+            }
+            _ => unreachable!("OP_METHOD always follows its class on the stack"),
         }
+
+        self.pop()?;
+        Ok(())
     }
 
-    pub(crate) fn read_constant(&mut self) -> Value {
-        return self.chunk.constants[*self.ip.next().unwrap() as usize];
+    fn call(&mut self, closure: *mut ObjClosure, arg_count: usize) -> Result<(), RuntimeError> {
+        let arity = unsafe { (*(*closure).function).arity };
+
+        if arg_count != arity {
+            return Err(RuntimeError::new(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(RuntimeError::new("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    fn current_upvalue(&self, slot: usize) -> *mut ObjUpvalue {
+        unsafe { (&(*self.frame().closure).upvalues)[slot] }
+    }
+
+    /// Reuses the open upvalue for `location` if one exists so closures
+    /// capturing the same variable share it.
+    fn capture_upvalue(&mut self, location: usize) -> *mut ObjUpvalue {
+        let mut prev_upvalue: *mut ObjUpvalue = ptr::null_mut();
+        let mut upvalue = self.open_upvalues;
+
+        unsafe {
+            while !upvalue.is_null() && (*upvalue).location > location {
+                prev_upvalue = upvalue;
+                upvalue = (*upvalue).next;
+            }
+
+            if !upvalue.is_null() && (*upvalue).location == location {
+                return upvalue;
+            }
+
+            let created_upvalue = self.new_upvalue(location);
+            (*created_upvalue).next = upvalue;
+
+            if prev_upvalue.is_null() {
+                self.open_upvalues = created_upvalue;
+            } else {
+                (*prev_upvalue).next = created_upvalue;
+            }
+
+            created_upvalue
+        }
+    }
+
+    /// Closes every open upvalue that refers to `last` or a slot above it.
+    fn close_upvalues(&mut self, last: usize) {
+        unsafe {
+            while !self.open_upvalues.is_null() && (*self.open_upvalues).location >= last {
+                let upvalue = self.open_upvalues;
+                (*upvalue).closed = Some(self.stack[(*upvalue).location]);
+                self.open_upvalues = (*upvalue).next;
+            }
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames
+            .last()
+            .expect("Code only runs inside a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("Code only runs inside a call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        unsafe { &(*(*self.frame().closure).function).chunk }
+    }
+
+    pub(crate) fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = unsafe { (&(*(*frame.closure).function).chunk.code)[frame.ip] };
+        frame.ip += 1;
+        byte
+    }
+
+    pub(crate) fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
+    /// Reads a little-endian constant index `width` bytes wide and returns
+    /// the constant.
+    pub(crate) fn read_constant(&mut self, width: usize) -> Value {
+        let mut index = [0u8; 4];
+        for byte in &mut index[..width] {
+            *byte = self.read_byte();
+        }
+
+        self.chunk().constants[u32::from_le_bytes(index) as usize]
+    }
+
+    pub(crate) fn read_string(&mut self, width: usize) -> *mut ObjString {
+        match self.read_constant(width) {
+            Value::Obj(object) => object as *mut ObjString,
+            _ => unreachable!("The compiler only emits string constants for names"),
+        }
+    }
+
+    fn undefined_variable(name: *mut ObjString) -> RuntimeError {
+        RuntimeError::new(format!("Undefined variable '{}'.", unsafe {
+            &(*name).chars
+        }))
+    }
+
+    fn pop_class(&mut self) -> Result<*mut ObjClass, RuntimeError> {
+        match self.pop()? {
+            Value::Obj(class) => Ok(class as *mut ObjClass),
+            _ => unreachable!("The compiler only loads classes as `super`"),
+        }
+    }
+
+    fn undefined_property(name: *mut ObjString) -> RuntimeError {
+        RuntimeError::new(format!("Undefined property '{}'.", unsafe {
+            &(*name).chars
+        }))
     }
 
     pub(crate) fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
 
-    pub(crate) fn pop(&mut self) -> Value {
-        self.stack.pop().expect("The stack is empty!")
+    pub(crate) fn peek(&self, distance: usize) -> Result<Value, RuntimeError> {
+        self.stack
+            .len()
+            .checked_sub(distance + 1)
+            .map(|index| self.stack[index])
+            .ok_or_else(|| RuntimeError::new("Stack underflow."))
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.stack
+            .pop()
+            .ok_or_else(|| RuntimeError::new("Stack underflow."))
+    }
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        self.free_objects();
+    }
+}
+
+#[cfg(test)]
+impl VM {
+    /// Interprets `source`, which has to run without errors, and formats the
+    /// global `name` it leaves behind the way `print` would.
+    pub(crate) fn interpret_global(&mut self, source: &str, name: &str) -> String {
+        assert!(
+            matches!(self.interpret(source), InterpretResult::InterpretOk),
+            "Failed to run {}",
+            source
+        );
+
+        let name = self.copy_string(name);
+        self.globals
+            .get(name)
+            .expect("The program defines the global")
+            .to_string()
+    }
+
+    /// Runs `source` up to the runtime error it has to raise, leaving the
+    /// frames that were active on the stack.
+    pub(crate) fn run_until_error(&mut self, source: &str) -> RuntimeError {
+        let function = compiler::compile(source, self)
+            .unwrap_or_else(|_| panic!("Failed to compile {}", source));
+
+        self.push(function.into());
+        let closure = self.new_closure(function);
+        self.stack.pop();
+        self.push(closure.into());
+
+        match self.call(closure, 0).and_then(|()| self.execute()) {
+            Ok(()) => panic!("No runtime error in {}", source),
+            Err(error) => error,
+        }
+    }
+
+    /// The message of the runtime error `source` has to raise.
+    pub(crate) fn runtime_error_message(&mut self, source: &str) -> String {
+        let error = self.run_until_error(source);
+        self.reset_stack();
+        error.message
+    }
+
+    /// The message of every compile error in `source`.
+    pub(crate) fn compile_error_messages(&mut self, source: &str) -> Vec<String> {
+        match compiler::compile(source, self) {
+            Ok(_) => panic!("No compile errors in {}", source),
+            Err(diagnostics) => diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn global(vm: &mut VM, name: &str) -> Option<Value> {
+        let name = vm.copy_string(name);
+        vm.globals.get(name)
+    }
+
+    #[test]
+    fn constants_past_one_byte_use_op_constant_long() {
+        const COUNT: usize = 70_000;
+
+        let terms: Vec<String> = (0..COUNT).map(|n| n.to_string()).collect();
+
+        // Every name below is only added to the pool after the literals, so
+        // each of its instructions needs a long index too
+        let source = format!(
+            "var result = {};
+var after = result;
+after = after + 1;
+fun function() {{ return after; }}
+class Base {{ base() {{ return 2; }} }}
+class Derived < Base {{
+  method() {{ this.field = super.base(); return this.field + super.base(); }}
+}}
+var instance = Derived();
+var method = instance.method;
+var called = instance.method();
+var closure = function();
+",
+            terms.join(" + ")
+        );
+
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret(&source),
+            InterpretResult::InterpretOk
+        ));
+
+        let expected = (COUNT * (COUNT - 1) / 2) as f64;
+        assert!(matches!(global(&mut vm, "result"), Some(Value::Number(n)) if n == expected));
+        assert!(
+            matches!(global(&mut vm, "closure"), Some(Value::Number(n)) if n == expected + 1.0)
+        );
+        assert_eq!(vm.interpret_global("", "called"), "4");
+        assert_eq!(vm.interpret_global("", "method"), "<fn method>");
+    }
+
+    #[test]
+    fn runtime_errors_close_escaped_upvalues() {
+        let mut vm = VM::new();
+
+        assert!(matches!(
+            vm.interpret(
+                "var f; fun g() { var x = 1; fun h() { return x; } f = h; nil + 1; } g();"
+            ),
+            InterpretResult::InterpretRuntimeError
+        ));
+        assert!(matches!(
+            vm.interpret("var result = f();"),
+            InterpretResult::InterpretOk
+        ));
+        assert!(matches!(global(&mut vm, "result"), Some(Value::Number(n)) if n == 1.0));
+    }
+
+    #[test]
+    fn runtime_errors_show_each_frame_in_its_own_source() {
+        let mut vm = VM::new();
+
+        assert!(matches!(
+            vm.interpret("fun f() {\n  return nil + 1;\n}\n"),
+            InterpretResult::InterpretOk
+        ));
+
+        // The next REPL line, stopped at the error so its frames remain
+        let error = vm.run_until_error("var x;\nf();\n");
+
+        assert_eq!(
+            Emitter::new().render(&vm.runtime_diagnostic(&error)),
+            "error: Operands must be two numbers or two strings.
+ --> <repl>:2:14
+  |
+2 |   return nil + 1;
+  |              ^
+  = note: in f()
+note: in script
+ --> <repl>:2:2
+  |
+2 | f();
+  |  ^
+
+"
+        );
+    }
+
+    #[test]
+    fn comparisons_and_truthiness() {
+        let mut vm = VM::new();
+        let source = "
+var comparisons = str(1 != 2) + \" \" + str(1 != 1) + \" \" + str(2 >= 2) + \" \" +
+  str(1 >= 2) + \" \" + str(2 <= 2) + \" \" + str(3 <= 2);
+var falsey = str(!nil) + \" \" + str(!false);
+var truthy = str(!0) + \" \" + str(!\"\") + \" \" + str(!true);
+var mixed = nil != false;
+";
+
+        assert_eq!(
+            vm.interpret_global(source, "comparisons"),
+            "true false true false true false"
+        );
+        assert_eq!(vm.interpret_global("", "falsey"), "true true");
+        assert_eq!(vm.interpret_global("", "truthy"), "false false false");
+        assert_eq!(vm.interpret_global("", "mixed"), "true");
+    }
+
+    #[test]
+    fn variables_must_be_defined_before_use() {
+        let mut vm = VM::new();
+
+        assert_eq!(
+            vm.runtime_error_message("print x;"),
+            "Undefined variable 'x'."
+        );
+        assert_eq!(
+            vm.runtime_error_message("x = 1;"),
+            "Undefined variable 'x'."
+        );
+        assert_eq!(
+            vm.compile_error_messages("{ var a = 1; { var a = a; } }"),
+            ["Can't read local variable in its own initializer."]
+        );
+        assert_eq!(
+            vm.interpret_global(
+                "var a = 1; { var a = 2; { var b = a; a = b + 1; } a = a + 1; }",
+                "a"
+            ),
+            "1"
+        );
+    }
+
+    #[test]
+    fn control_flow_short_circuits_branches_and_loops() {
+        let mut vm = VM::new();
+        let source = "
+var or_value = nil or \"x\";
+var and_value = false and 1;
+var skipped = \"untouched\";
+false and (skipped = \"evaluated\");
+true or (skipped = \"evaluated\");
+
+var branch;
+if (1 > 2) branch = \"then\"; else branch = \"else\";
+
+var sum = 0;
+var i = 0;
+while (i < 5) {
+  sum = sum + i;
+  i = i + 1;
+}
+for (var j = 0; j < 3; j = j + 1) sum = sum + 10;
+";
+
+        assert_eq!(vm.interpret_global(source, "or_value"), "x");
+        assert_eq!(vm.interpret_global("", "and_value"), "false");
+        assert_eq!(vm.interpret_global("", "skipped"), "untouched");
+        assert_eq!(vm.interpret_global("", "branch"), "else");
+        assert_eq!(vm.interpret_global("", "sum"), "40");
+    }
+
+    #[test]
+    fn recursive_frames_are_collapsed() {
+        let mut vm = VM::new();
+        let error = vm.run_until_error("fun f() {\n  f();\n}\nf();\n");
+        let diagnostic = vm.runtime_diagnostic(&error);
+
+        assert_eq!(diagnostic.message, "Stack overflow.");
+        assert_eq!(diagnostic.location.span.line, 2);
+
+        let notes: Vec<(&str, Option<i32>)> = diagnostic
+            .notes
+            .iter()
+            .map(|note| {
+                let line = note.location.as_ref().map(|location| location.span.line);
+                (note.message.as_str(), line)
+            })
+            .collect();
+        assert_eq!(
+            notes,
+            [("in f() (62 more times)", None), ("in script", Some(4))]
+        );
+    }
+
+    #[test]
+    fn functions_are_values_that_can_recurse() {
+        let mut vm = VM::new();
+        let source = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 2) + fib(n - 1);
+}
+fun apply(f, a) { return f(a); }
+fun discard(a) { a; }
+var result = apply(fib, 15);
+var name = fib;
+var nothing = apply(discard, 1);
+";
+
+        assert_eq!(vm.interpret_global(source, "result"), "610");
+        assert_eq!(vm.interpret_global("", "name"), "<fn fib>");
+        assert_eq!(vm.interpret_global("", "nothing"), "nil");
+    }
+
+    #[test]
+    fn bad_calls_are_runtime_errors() {
+        let mut vm = VM::new();
+
+        for (source, message) in [
+            ("fun f(a) {} f();", "Expected 1 arguments but got 0."),
+            ("fun f() { f(); } f();", "Stack overflow."),
+            (
+                "var notfn = 1; notfn();",
+                "Can only call functions and classes.",
+            ),
+        ] {
+            assert_eq!(vm.runtime_error_message(source), message, "{}", source);
+        }
+
+        assert!(matches!(
+            vm.interpret("fun f() { f(); } f();"),
+            InterpretResult::InterpretRuntimeError
+        ));
+        assert!(vm.frames.is_empty() && vm.stack.is_empty());
+
+        // The VM is still usable afterwards
+        assert_eq!(
+            vm.interpret_global("fun f(a) { return a; } var result = f(2);", "result"),
+            "2"
+        );
+    }
+
+    #[test]
+    fn closures_share_and_outlive_captured_variables() {
+        let mut vm = VM::new();
+        let source = "
+fun counter() {
+  var count = 0;
+  fun increment() { count = count + 1; return count; }
+  return increment;
+}
+var a = counter();
+var b = counter();
+a(); a();
+var counts = str(a()) + \",\" + str(b());
+
+var get; var set;
+{
+  var shared = \"before\";
+  fun g() { return shared; }
+  fun s(value) { shared = value; }
+  get = g; set = s;
+}
+set(\"after\");
+var shared = get();
+
+var first;
+for (var i = 0; i < 3; i = i + 1) {
+  var j = i;
+  fun f() { return j; }
+  if (first == nil) first = f;
+}
+var closed = first();
+";
+
+        assert_eq!(vm.interpret_global(source, "counts"), "3,1");
+        assert_eq!(vm.interpret_global("", "shared"), "after");
+        assert_eq!(vm.interpret_global("", "closed"), "0");
+        assert!(vm.open_upvalues.is_null());
+    }
+
+    #[test]
+    fn instances_hold_fields() {
+        let mut vm = VM::new();
+        let source = "
+class Point {}
+var point = Point();
+point.x = 1;
+point.y = point.x + 1;
+point.x = point.x + point.y;
+var sum = point.x + point.y;
+";
+
+        assert_eq!(vm.interpret_global(source, "sum"), "5");
+        assert_eq!(vm.interpret_global("", "Point"), "Point");
+        assert_eq!(vm.interpret_global("", "point"), "Point instance");
+
+        for (source, message) in [
+            ("point.missing;", "Undefined property 'missing'."),
+            (
+                "var number = 1; number.field;",
+                "Only instances have properties.",
+            ),
+            (
+                "var number = 1; number.field = 2;",
+                "Only instances have fields.",
+            ),
+            ("Point(1);", "Expected 0 arguments but got 1."),
+        ] {
+            assert_eq!(vm.runtime_error_message(source), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn methods_bind_this() {
+        let mut vm = VM::new();
+        let source = "
+class Counter {
+  init(start) { this.count = start; }
+  increment() { this.count = this.count + 1; return this; }
+  get() { return this.count; }
+}
+var counter = Counter(10);
+counter.increment().increment();
+var bound = counter.get;
+var count = bound();
+var initialized = counter.init(0) == counter;
+fun field() { return 5; }
+counter.get = field;
+var field_first = counter.get();
+";
+
+        assert_eq!(vm.interpret_global(source, "count"), "12");
+        assert_eq!(vm.interpret_global("", "bound"), "<fn get>");
+        assert_eq!(vm.interpret_global("", "initialized"), "true");
+        assert_eq!(vm.interpret_global("", "field_first"), "5");
+
+        for (source, message) in [
+            ("Counter();", "Expected 1 arguments but got 0."),
+            ("counter.missing();", "Undefined property 'missing'."),
+            (
+                "var number = 1; number.method();",
+                "Only instances have methods.",
+            ),
+        ] {
+            assert_eq!(vm.runtime_error_message(source), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn subclasses_inherit_and_call_super() {
+        let mut vm = VM::new();
+        let source = "
+class A {
+  init(name) { this.name = name; }
+  greet() { return \"A \" + this.name; }
+  only_in_a() { return \"inherited\"; }
+}
+class B < A {
+  init(name) { super.init(\"b \" + name); }
+  greet() { return \"B \" + super.greet(); }
+}
+class C < B {
+  greet() {
+    var method = super.greet;
+    return \"C \" + method();
+  }
+}
+var greeting = C(\"c\").greet();
+var inherited = C(\"c\").only_in_a();
+";
+
+        assert_eq!(vm.interpret_global(source, "greeting"), "C B A b c");
+        assert_eq!(vm.interpret_global("", "inherited"), "inherited");
+
+        assert_eq!(
+            vm.runtime_error_message("var NotAClass = 1; class D < NotAClass {}"),
+            "Superclass must be a class."
+        );
+
+        for (source, message) in [
+            ("class E < E {}", "A class can't inherit from itself."),
+            ("super.greet();", "Can't use 'super' outside of a class."),
+            (
+                "class F { greet() { return super.greet(); } }",
+                "Can't use 'super' in a class with no superclass.",
+            ),
+        ] {
+            assert_eq!(vm.compile_error_messages(source), [message], "{}", source);
+        }
     }
 }